@@ -1,42 +1,294 @@
-use zmachine::{ZMachineExecResult, ZMachine};
+use zmachine::{ZMachineExecResult, ZMachine, StatusLine, StatusProgress, ZSound, SoundAction};
 
 use std::io;
 use std::io::Write;
 use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
 
 use textwrap::fill;
 
+fn flush(output: &mut Vec<u8>) {
+    let out = String::from_utf8(std::mem::take(output)).unwrap();
+    let wrapped = fill(&out, 80);
+    io::stdout().write_all(wrapped.as_bytes()).unwrap();
+    io::stdout().flush().unwrap();
+}
+
+// no sound resources, but the built-in bleeps can ring the terminal bell
+struct Bell;
+
+impl ZSound for Bell {
+    fn sound_effect(&mut self, number: u16, action: SoundAction, _volume: u8, _repeats: u8) {
+        if (number == 1 || number == 2) && action == SoundAction::Start {
+            print!("\x07");
+            io::stdout().flush().unwrap();
+        }
+    }
+}
+
+fn print_status(status: &StatusLine) {
+    let right = match status.progress {
+        StatusProgress::Score { score, turns } => format!("Score: {}  Moves: {}", score, turns),
+        StatusProgress::Time { hours, minutes } => {
+            let suffix = if hours < 12 { "am" } else { "pm" };
+            let hours = match hours % 12 {
+                0 => 12,
+                h => h,
+            };
+            format!("Time: {}:{:02} {}", hours, minutes, suffix)
+        },
+    };
+
+    let width = 80 - right.len() - 1;
+    println!("\x1b[7m {:<width$}{} \x1b[0m", status.location, right, width = width - 1);
+}
+
+fn print_upper_window(lines: &[String]) {
+    if lines.iter().all(|line| line.is_empty()) {
+        return;
+    }
+
+    for line in lines {
+        println!("{}", line);
+    }
+    println!("{}", "-".repeat(80));
+}
+
+// stdin is read on its own thread so timed input can stop waiting for it
+struct Keyboard {
+    lines: mpsc::Receiver<String>,
+}
+
+impl Keyboard {
+    fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                Ok(n) if n > 0 => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                },
+                _ => break,
+            }
+        });
+
+        Keyboard { lines: rx }
+    }
+
+    // None once stdin is closed
+    fn read_line(&self) -> Option<String> {
+        self.lines.recv().ok()
+    }
+}
+
+fn prompt(keyboard: &Keyboard, message: &str) -> String {
+    print!("{}", message);
+    io::stdout().flush().unwrap();
+
+    keyboard.read_line().unwrap_or_default().trim().to_string()
+}
+
+fn save_file_name(keyboard: &Keyboard, default: &str) -> String {
+    let name = prompt(keyboard, &format!("\nSave file name [{}]: ", default));
+    if name.is_empty() {
+        default.to_string()
+    } else {
+        name
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    game: String,
+    transcript: Option<String>,
+    record: Option<String>,
+    replay: Option<String>,
+    seed: Option<u64>,
+}
+
+fn parse_args() -> Option<Options> {
+    let mut options = Options::default();
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--transcript" => options.transcript = Some(args.next()?),
+            "--record" => options.record = Some(args.next()?),
+            "--replay" => options.replay = Some(args.next()?),
+            "--seed" => options.seed = Some(args.next()?.parse().ok()?),
+            _ => options.game = arg,
+        }
+    }
+
+    if options.game.is_empty() {
+        None
+    } else {
+        Some(options)
+    }
+}
+
+fn open_log(name: &str) -> Option<Box<dyn Write>> {
+    match fs::File::create(name) {
+        Ok(f) => Some(Box::new(io::BufWriter::new(f))),
+        Err(e) => {
+            println!("couldn't open {}: {}", name, e);
+            None
+        },
+    }
+}
+
 fn main() {
     let mut machine = ZMachine::new();
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("no args");
-    } else {
-        let mut game = String::from("./games/");
-        game.push_str(&args[1]);
+    machine.set_sound(Some(Box::new(Bell)));
+    if let Some(options) = parse_args() {
+        // bare names are looked up in games/ for convenience
+        let mut game = options.game.clone();
+        if !Path::new(&game).exists() {
+            game = format!("./games/{}", options.game);
+        }
+        let default_save = format!("{}.qzl", options.game);
 
         let mut output: Vec<u8> = Vec::new();
+        let mut status: Option<StatusLine> = None;
+        let keyboard = Keyboard::new();
 
         match machine.load(&game) {
-            Ok(()) => {
+            Ok(warnings) => {
+                for warning in warnings {
+                    println!("warning: {}", warning);
+                }
+
+                // games that don't use their own undo get ours
+                let host_undo = machine.header().map(|header| !header.wants_undo()).unwrap_or(false);
+
+                if let Some(seed) = options.seed {
+                    machine.seed_random(seed);
+                }
+                if let Some(name) = options.transcript.as_ref() {
+                    machine.set_transcript(open_log(name));
+                }
+                if let Some(name) = options.record.as_ref() {
+                    machine.set_command_log(open_log(name));
+                }
+                if let Some(name) = options.replay.as_ref() {
+                    match fs::File::open(name) {
+                        Ok(f) => machine.set_command_source(Some(Box::new(io::BufReader::new(f)))),
+                        Err(e) => println!("couldn't open {}: {}", name, e),
+                    }
+                }
+
                 loop {
-                    match machine.exec(&mut output) {
-                        ZMachineExecResult::NeedInput => {
-                            let out = String::from_utf8(output).unwrap();
-                            let wrapped = format!("{}", fill(&out, 80));
-                            io::stdout().write_all(wrapped.as_bytes()).unwrap();
-                            io::stdout().flush().unwrap();
-                            output = Vec::new();
-
-                            let mut input = String::new();
-                            io::stdin().read_line(&mut input).expect("couldn't read from stdin");
-                            machine.send_input(&input);
+                    let result = machine.exec(&mut output);
+                    match result {
+                        ZMachineExecResult::ShowStatus(line) => {
+                            status.replace(line);
+                        },
+                        ZMachineExecResult::NeedInput |
+                        ZMachineExecResult::NeedChar |
+                        ZMachineExecResult::NeedTimedInput(_) |
+                        ZMachineExecResult::NeedTimedChar(_) => {
+                            if let Some(line) = status.take() {
+                                print_status(&line);
+                            }
+                            print_upper_window(&machine.upper_window());
+                            flush(&mut output);
+
+                            let input = match machine.time_left() {
+                                Some(left) => match keyboard.lines.recv_timeout(left) {
+                                    Ok(input) => input,
+                                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                                        machine.send_timeout();
+                                        continue;
+                                    },
+                                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                                },
+                                None => match keyboard.read_line() {
+                                    Some(input) => input,
+                                    None => break,
+                                },
+                            };
+
+                            let sent = match result {
+                                // the terminal hands over whole lines, the first key is the one that counts
+                                ZMachineExecResult::NeedChar | ZMachineExecResult::NeedTimedChar(_) => {
+                                    let key = input.trim_end_matches(&['\r', '\n'][..]).chars().next().unwrap_or('\n');
+                                    machine.send_char(key)
+                                },
+                                _ if host_undo && input.trim().eq_ignore_ascii_case("undo") => {
+                                    if machine.undo() {
+                                        print!("[Previous turn undone.]\n\n>");
+                                    } else {
+                                        print!("[Can't undo any further.]\n\n>");
+                                    }
+                                    continue;
+                                },
+                                // the game has already printed any preloaded text, so the player carries on from it
+                                _ => {
+                                    if host_undo {
+                                        machine.checkpoint();
+                                    }
+                                    machine.send_input(&(machine.preloaded_input() + &input))
+                                },
+                            };
+                            if let Err(e) = sent {
+                                println!("\n*** the story crashed: {}", e);
+                                break;
+                            }
+                        },
+                        ZMachineExecResult::Save(data) => {
+                            flush(&mut output);
+
+                            let name = save_file_name(&keyboard, &default_save);
+                            let saved = match fs::write(&name, data) {
+                                Ok(()) => true,
+                                Err(e) => {
+                                    println!("couldn't save to {}: {}", name, e);
+                                    false
+                                },
+                            };
+                            if let Err(e) = machine.send_save_result(saved) {
+                                println!("\n*** the story crashed: {}", e);
+                                break;
+                            }
+                        },
+                        ZMachineExecResult::Restore => {
+                            flush(&mut output);
+
+                            let name = save_file_name(&keyboard, &default_save);
+                            match fs::read(&name) {
+                                Ok(data) => {
+                                    if let Err(e) = machine.send_restore(Some(&data)) {
+                                        println!("couldn't restore from {}: {}", name, e);
+                                    }
+                                },
+                                Err(e) => {
+                                    println!("couldn't read {}: {}", name, e);
+                                    if let Err(e) = machine.send_restore(None) {
+                                        println!("couldn't restore from {}: {}", name, e);
+                                    }
+                                },
+                            }
+                        },
+                        ZMachineExecResult::Error(e) => {
+                            flush(&mut output);
+                            println!("\n*** the story crashed: {}", e);
+                            break;
+                        },
+                        _ => {
+                            flush(&mut output);
+                            break;
                         },
-                        _ => break,
                     }
                 }
             },
             Err(e) => println!("whoops {}", e),
         };
+    } else {
+        println!("usage: zcli [--transcript FILE] [--record FILE] [--replay FILE] [--seed N] GAME");
     }
 }
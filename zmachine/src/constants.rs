@@ -2,6 +2,9 @@
 pub mod instr {
     pub const SHORT_INSTRUCTION: u8 = 0x02;
     pub const VAR_INSTRUCTION: u8 = 0x03;
+    pub const EXTENDED: u8 = 0xBE;
+    pub const CALL_VS2: u8 = 0xEC;
+    pub const CALL_VN2: u8 = 0xFA;
 }
 
 pub mod operand {
@@ -8,11 +8,27 @@ mod zmemory;
 mod zinst;
 mod zstr;
 mod constants;
+mod quetzal;
+mod zscreen;
+mod zstream;
+mod zsound;
+mod ztimer;
+mod zundo;
+mod zerror;
+mod zheader;
+mod zprofile;
 
 #[macro_use]
 extern crate typenum;
 
-pub use zmachine::{ZMachine, ZMachineExecResult};
+pub use zmachine::{ZMachine, ZMachineExecResult, ExecError, LoadError, LoadWarning, StatusLine, StatusProgress};
+pub use zinst::InstructionType;
+pub use quetzal::QuetzalError;
+pub use zsound::{ZSound, SoundAction};
+pub use ztimer::ZClock;
+pub use zerror::ZMachineError;
+pub use zheader::ZHeader;
+pub use zprofile::{InterpreterProfile, Infocom};
 
 #[cfg(test)]
 mod tests {
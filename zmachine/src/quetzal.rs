@@ -0,0 +1,280 @@
+use std::fmt;
+use std::convert::TryInto;
+
+#[derive(Debug)]
+pub enum QuetzalError {
+    NotQuetzal,
+    MissingChunk(&'static str),
+    Malformed(&'static str),
+    WrongStory,
+}
+
+impl fmt::Display for QuetzalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuetzalError::NotQuetzal => write!(f, "not a quetzal save file"),
+            QuetzalError::MissingChunk(id) => write!(f, "save file has no {} chunk", id),
+            QuetzalError::Malformed(what) => write!(f, "malformed save file: {}", what),
+            QuetzalError::WrongStory => write!(f, "save file belongs to a different story"),
+        }
+    }
+}
+
+impl std::error::Error for QuetzalError {}
+
+#[derive(Debug, Default)]
+pub(crate) struct QuetzalFrame {
+    pub(crate) return_pc: usize,
+    pub(crate) discard: bool,
+    pub(crate) store: u8,
+    pub(crate) args: u8,
+    pub(crate) locals: Vec<u16>,
+    pub(crate) stack: Vec<u16>,
+}
+
+#[derive(Debug)]
+pub(crate) struct QuetzalSave {
+    pub(crate) release: u16,
+    pub(crate) serial: [u8; 6],
+    pub(crate) checksum: u16,
+    pub(crate) pc: usize,
+    pub(crate) memory: Vec<u8>,
+    pub(crate) frames: Vec<QuetzalFrame>,
+}
+
+fn push_chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn push_pc(out: &mut Vec<u8>, pc: usize) {
+    let [_, hi, mid, lo] = (pc as u32).to_be_bytes();
+    out.extend_from_slice(&[hi, mid, lo]);
+}
+
+fn read_pc(data: &[u8]) -> usize {
+    u32::from_be_bytes([0, data[0], data[1], data[2]]) as usize
+}
+
+// XOR against the original memory, then run-length encode the zeros.
+fn compress(memory: &[u8], original: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut zeros = 0usize;
+
+    for (b, o) in memory.iter().zip(original.iter()) {
+        let x = b ^ o;
+        if x == 0 {
+            zeros += 1;
+            continue;
+        }
+
+        while zeros > 0 {
+            let run = zeros.min(256);
+            out.push(0);
+            out.push((run - 1) as u8);
+            zeros -= run;
+        }
+        out.push(x);
+    }
+
+    // trailing zeros are implied
+    out
+}
+
+fn decompress(data: &[u8], original: &[u8]) -> Result<Vec<u8>, QuetzalError> {
+    let mut memory = original.to_vec();
+    let mut idx = 0usize;
+    let mut iter = data.iter();
+
+    while let Some(b) = iter.next() {
+        if *b == 0 {
+            let run = *iter.next().ok_or(QuetzalError::Malformed("truncated CMem run"))? as usize;
+            idx += run + 1;
+        } else {
+            let byte = memory.get_mut(idx).ok_or(QuetzalError::Malformed("CMem overflows dynamic memory"))?;
+            *byte ^= b;
+            idx += 1;
+        }
+
+        if idx > original.len() {
+            return Err(QuetzalError::Malformed("CMem overflows dynamic memory"));
+        }
+    }
+
+    Ok(memory)
+}
+
+impl QuetzalSave {
+    pub(crate) fn to_bytes(&self, original: &[u8]) -> Vec<u8> {
+        let mut ifhd = Vec::new();
+        ifhd.extend_from_slice(&self.release.to_be_bytes());
+        ifhd.extend_from_slice(&self.serial);
+        ifhd.extend_from_slice(&self.checksum.to_be_bytes());
+        push_pc(&mut ifhd, self.pc);
+
+        let cmem = compress(&self.memory, original);
+
+        let mut stks = Vec::new();
+        for frame in self.frames.iter() {
+            push_pc(&mut stks, frame.return_pc);
+            let mut flags = frame.locals.len() as u8 & 0x0f;
+            if frame.discard {
+                flags |= 0x10;
+            }
+            stks.push(flags);
+            stks.push(frame.store);
+            stks.push(frame.args);
+            stks.extend_from_slice(&(frame.stack.len() as u16).to_be_bytes());
+            for v in frame.locals.iter().chain(frame.stack.iter()) {
+                stks.extend_from_slice(&v.to_be_bytes());
+            }
+        }
+
+        let mut body = Vec::new();
+        body.extend_from_slice(b"IFZS");
+        push_chunk(&mut body, b"IFhd", &ifhd);
+        push_chunk(&mut body, b"CMem", &cmem);
+        push_chunk(&mut body, b"Stks", &stks);
+
+        let mut out = Vec::new();
+        out.extend_from_slice(b"FORM");
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend(body);
+
+        out
+    }
+
+    pub(crate) fn from_bytes(data: &[u8], original: &[u8]) -> Result<QuetzalSave, QuetzalError> {
+        if data.len() < 12 || &data[0..4] != b"FORM" || &data[8..12] != b"IFZS" {
+            return Err(QuetzalError::NotQuetzal);
+        }
+
+        let form_len = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+        let end = (form_len + 8).min(data.len());
+
+        let mut ifhd: Option<&[u8]> = None;
+        let mut memory: Option<Vec<u8>> = None;
+        let mut stks: Option<&[u8]> = None;
+
+        let mut idx = 12;
+        while idx + 8 <= end {
+            let id = &data[idx..idx + 4];
+            let len = u32::from_be_bytes(data[idx + 4..idx + 8].try_into().unwrap()) as usize;
+            let start = idx + 8;
+            let chunk = data.get(start..start + len).ok_or(QuetzalError::Malformed("truncated chunk"))?;
+
+            match id {
+                b"IFhd" => { ifhd.replace(chunk); },
+                b"CMem" => { memory.replace(decompress(chunk, original)?); },
+                b"UMem" => {
+                    if chunk.len() != original.len() {
+                        return Err(QuetzalError::Malformed("UMem is the wrong size"));
+                    }
+                    memory.replace(chunk.to_vec());
+                },
+                b"Stks" => { stks.replace(chunk); },
+                _ => {}, // IntD, ANNO and friends are safe to skip
+            }
+
+            idx = start + len + len % 2;
+        }
+
+        let ifhd = ifhd.ok_or(QuetzalError::MissingChunk("IFhd"))?;
+        let memory = memory.ok_or(QuetzalError::MissingChunk("CMem or UMem"))?;
+        let stks = stks.ok_or(QuetzalError::MissingChunk("Stks"))?;
+
+        if ifhd.len() < 13 {
+            return Err(QuetzalError::Malformed("IFhd is too short"));
+        }
+
+        let mut frames = Vec::new();
+        let mut idx = 0;
+        while idx < stks.len() {
+            let header = stks.get(idx..idx + 8).ok_or(QuetzalError::Malformed("truncated stack frame"))?;
+            let n_locals = (header[3] & 0x0f) as usize;
+            let n_stack = u16::from_be_bytes([header[6], header[7]]) as usize;
+            idx += 8;
+
+            let words = stks.get(idx..idx + (n_locals + n_stack) * 2)
+                .ok_or(QuetzalError::Malformed("truncated stack frame"))?;
+            let mut values = words.chunks(2).map(|w| u16::from_be_bytes([w[0], w[1]]));
+            idx += words.len();
+
+            frames.push(QuetzalFrame {
+                return_pc: read_pc(&header[0..3]),
+                discard: header[3] & 0x10 != 0,
+                store: header[4],
+                args: header[5],
+                locals: values.by_ref().take(n_locals).collect(),
+                stack: values.collect(),
+            });
+        }
+
+        if frames.is_empty() {
+            return Err(QuetzalError::Malformed("empty stack"));
+        }
+
+        Ok(QuetzalSave {
+            release: u16::from_be_bytes([ifhd[0], ifhd[1]]),
+            serial: ifhd[2..8].try_into().unwrap(),
+            checksum: u16::from_be_bytes([ifhd[8], ifhd[9]]),
+            pc: read_pc(&ifhd[10..13]),
+            memory,
+            frames,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cmem_round_trip() {
+        let original: Vec<u8> = (0..1024).map(|i| (i % 251) as u8).collect();
+        let mut memory = original.clone();
+        memory[3] = 0xff;
+        memory[700] ^= 0x10;
+        memory[701] ^= 0x01;
+
+        let packed = compress(&memory, &original);
+        assert!(packed.len() < 16);
+        assert_eq!(decompress(&packed, &original).unwrap(), memory);
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let original = vec![0u8; 64];
+        let mut memory = original.clone();
+        memory[10] = 42;
+
+        let save = QuetzalSave {
+            release: 88,
+            serial: *b"840726",
+            checksum: 0xa129,
+            pc: 0x12345,
+            memory,
+            frames: vec![
+                QuetzalFrame { stack: vec![1, 2], ..Default::default() },
+                QuetzalFrame { return_pc: 0x4f05, store: 0x10, locals: vec![7, 8, 9], ..Default::default() },
+            ],
+        };
+
+        let bytes = save.to_bytes(&original);
+        let restored = QuetzalSave::from_bytes(&bytes, &original).unwrap();
+
+        assert_eq!(restored.release, 88);
+        assert_eq!(&restored.serial, b"840726");
+        assert_eq!(restored.pc, 0x12345);
+        assert_eq!(restored.memory, save.memory);
+        assert_eq!(restored.frames.len(), 2);
+        assert_eq!(restored.frames[0].stack, vec![1, 2]);
+        assert_eq!(restored.frames[1].return_pc, 0x4f05);
+        assert_eq!(restored.frames[1].store, 0x10);
+        assert_eq!(restored.frames[1].locals, vec![7, 8, 9]);
+    }
+}
@@ -0,0 +1,40 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZMachineError {
+    InvalidObject(u16),
+    InvalidAttribute(u16),
+    InvalidProperty { object: u16, property: u16 },
+    InvalidVariable(u16),
+    StackUnderflow,
+    OutOfBounds(usize),
+    ReadOnly(usize),
+    DivideByZero,
+    BadOperandType(u8),
+    MissingOperand(usize),
+    UnknownOpcode(u8),
+    NoStory,
+}
+
+impl fmt::Display for ZMachineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZMachineError::InvalidObject(obj) => write!(f, "invalid object {}", obj),
+            ZMachineError::InvalidAttribute(attr) => write!(f, "invalid attribute {}", attr),
+            ZMachineError::InvalidProperty { object, property } => {
+                write!(f, "object {} has no property {}", object, property)
+            },
+            ZMachineError::InvalidVariable(var) => write!(f, "invalid variable {}", var),
+            ZMachineError::StackUnderflow => write!(f, "stack underflow"),
+            ZMachineError::OutOfBounds(addr) => write!(f, "address {:x} is out of bounds", addr),
+            ZMachineError::ReadOnly(addr) => write!(f, "address {:x} is not in dynamic memory", addr),
+            ZMachineError::DivideByZero => write!(f, "division by zero"),
+            ZMachineError::BadOperandType(ty) => write!(f, "unexpected operand type {}", ty),
+            ZMachineError::MissingOperand(n) => write!(f, "operand {} is missing", n),
+            ZMachineError::UnknownOpcode(op) => write!(f, "unknown opcode {}", op),
+            ZMachineError::NoStory => write!(f, "no story is loaded"),
+        }
+    }
+}
+
+impl std::error::Error for ZMachineError {}
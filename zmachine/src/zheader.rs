@@ -0,0 +1,371 @@
+use std::convert::TryInto;
+
+// A view over story memory, which starts with the 64 byte header. Setters are
+// only provided for the fields the standard lets an interpreter write.
+pub struct ZHeader<T: AsRef<[u8]>> {
+    data: T,
+}
+
+impl<T: AsRef<[u8]>> ZHeader<T> {
+    pub fn new(data: T) -> Option<ZHeader<T>> {
+        if data.as_ref().len() < 64 {
+            None
+        } else {
+            Some(ZHeader { data })
+        }
+    }
+
+    fn byte(&self, idx: usize) -> u8 {
+        self.data.as_ref()[idx]
+    }
+
+    fn word(&self, idx: usize) -> u16 {
+        let data = self.data.as_ref();
+        u16::from_be_bytes([data[idx], data[idx + 1]])
+    }
+
+    pub fn version(&self) -> u8 {
+        self.byte(0x00)
+    }
+
+    pub fn flags1(&self) -> u8 {
+        self.byte(0x01)
+    }
+
+    // V1-3: the status line shows hours and minutes instead of score and turns
+    pub fn time_game(&self) -> bool {
+        self.version() <= 3 && self.flags1() & 0x02 != 0
+    }
+
+    pub fn release(&self) -> u16 {
+        self.word(0x02)
+    }
+
+    pub fn high_memory(&self) -> usize {
+        self.word(0x04) as usize
+    }
+
+    pub fn initial_pc(&self) -> usize {
+        self.word(0x06) as usize
+    }
+
+    pub fn dictionary(&self) -> usize {
+        self.word(0x08) as usize
+    }
+
+    pub fn objects(&self) -> usize {
+        self.word(0x0A) as usize
+    }
+
+    pub fn globals(&self) -> usize {
+        self.word(0x0C) as usize
+    }
+
+    pub fn static_memory(&self) -> usize {
+        self.word(0x0E) as usize
+    }
+
+    pub fn flags2(&self) -> u16 {
+        self.word(0x10)
+    }
+
+    pub fn transcripting(&self) -> bool {
+        self.flags2() & 0x0001 != 0
+    }
+
+    pub fn fixed_pitch(&self) -> bool {
+        self.flags2() & 0x0002 != 0
+    }
+
+    pub fn wants_pictures(&self) -> bool {
+        self.flags2() & 0x0008 != 0
+    }
+
+    pub fn wants_undo(&self) -> bool {
+        self.flags2() & 0x0010 != 0
+    }
+
+    pub fn wants_mouse(&self) -> bool {
+        self.flags2() & 0x0020 != 0
+    }
+
+    pub fn wants_colours(&self) -> bool {
+        self.flags2() & 0x0040 != 0
+    }
+
+    pub fn wants_sound(&self) -> bool {
+        self.flags2() & 0x0080 != 0
+    }
+
+    pub fn wants_menus(&self) -> bool {
+        self.flags2() & 0x0100 != 0
+    }
+
+    pub fn serial(&self) -> [u8; 6] {
+        self.data.as_ref()[0x12..0x18].try_into().unwrap()
+    }
+
+    pub fn abbreviations(&self) -> usize {
+        self.word(0x18) as usize
+    }
+
+    // in bytes, already scaled for the version. 0 in very early stories.
+    pub fn file_length(&self) -> usize {
+        let scale = match self.version() {
+            1..=3 => 2,
+            4 | 5 => 4,
+            _ => 8,
+        };
+
+        self.word(0x1A) as usize * scale
+    }
+
+    pub fn checksum(&self) -> u16 {
+        self.word(0x1C)
+    }
+
+    pub fn interpreter_number(&self) -> u8 {
+        self.byte(0x1E)
+    }
+
+    pub fn interpreter_version(&self) -> u8 {
+        self.byte(0x1F)
+    }
+
+    // (lines, characters)
+    pub fn screen_size(&self) -> (u8, u8) {
+        (self.byte(0x20), self.byte(0x21))
+    }
+
+    // V5+: (width, height) in units
+    pub fn screen_units(&self) -> (u16, u16) {
+        (self.word(0x22), self.word(0x24))
+    }
+
+    // V5+: (width, height) of a character in units
+    pub fn font_size(&self) -> (u8, u8) {
+        match self.version() {
+            6 => (self.byte(0x27), self.byte(0x26)),
+            _ => (self.byte(0x26), self.byte(0x27)),
+        }
+    }
+
+    // V6/7 packed address offsets, already multiplied by 8
+    pub fn routines_offset(&self) -> usize {
+        self.word(0x28) as usize * 8
+    }
+
+    pub fn strings_offset(&self) -> usize {
+        self.word(0x2A) as usize * 8
+    }
+
+    // Packed addresses of routines and strings (1.2.3)
+    pub fn routine_address(&self, packed: u16) -> usize {
+        self.unpack(packed, self.routines_offset())
+    }
+
+    pub fn string_address(&self, packed: u16) -> usize {
+        self.unpack(packed, self.strings_offset())
+    }
+
+    fn unpack(&self, packed: u16, offset: usize) -> usize {
+        let packed = packed as usize;
+        match self.version() {
+            1..=3 => packed * 2,
+            4 | 5 => packed * 4,
+            6 | 7 => packed * 4 + offset,
+            _ => packed * 8,
+        }
+    }
+
+    pub fn terminating_chars(&self) -> usize {
+        self.word(0x2E) as usize
+    }
+
+    // (major, minor), (0, 0) if the interpreter doesn't claim to follow it
+    pub fn standard_revision(&self) -> (u8, u8) {
+        (self.byte(0x32), self.byte(0x33))
+    }
+
+    pub fn alphabet_table(&self) -> usize {
+        self.word(0x34) as usize
+    }
+
+    pub fn extension_table(&self) -> usize {
+        self.word(0x36) as usize
+    }
+
+    pub fn extension_len(&self) -> u16 {
+        match self.extension_table() {
+            0 => 0,
+            addr => self.read_word(addr).unwrap_or(0),
+        }
+    }
+
+    // Words in the extension table count from 1, missing ones read as 0.
+    pub fn extension(&self, n: u16) -> u16 {
+        if n == 0 || n > self.extension_len() {
+            return 0;
+        }
+
+        self.read_word(self.extension_table() + n as usize * 2).unwrap_or(0)
+    }
+
+    pub fn mouse(&self) -> (u16, u16) {
+        (self.extension(1), self.extension(2))
+    }
+
+    pub fn unicode_table(&self) -> usize {
+        self.extension(3) as usize
+    }
+
+    pub fn flags3(&self) -> u16 {
+        self.extension(4)
+    }
+
+    fn read_word(&self, idx: usize) -> Option<u16> {
+        let data = self.data.as_ref().get(idx..idx + 2)?;
+        Some(u16::from_be_bytes([data[0], data[1]]))
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> ZHeader<T> {
+    fn set_byte(&mut self, idx: usize, val: u8) {
+        self.data.as_mut()[idx] = val;
+    }
+
+    fn set_word(&mut self, idx: usize, val: u16) {
+        let [hi, lo] = val.to_be_bytes();
+        let data = self.data.as_mut();
+        data[idx] = hi;
+        data[idx + 1] = lo;
+    }
+
+    // Only the capability bits belong to the interpreter, the rest of the
+    // byte is left as the game set it.
+    pub fn set_flags1(&mut self, bits: u8) {
+        let mask = if self.version() <= 3 { 0x70 } else { 0xBF };
+        let flags1 = (self.flags1() & !mask) | (bits & mask);
+        self.set_byte(0x01, flags1);
+    }
+
+    pub fn set_transcripting(&mut self, on: bool) {
+        self.set_flags2_bit(0x0001, on);
+    }
+
+    pub fn set_fixed_pitch(&mut self, on: bool) {
+        self.set_flags2_bit(0x0002, on);
+    }
+
+    // The interpreter can turn down optional features the game asked for
+    // (pictures, undo, mouse, colours, sound and menus) but never grant them.
+    pub fn refuse_features(&mut self, bits: u16) {
+        let flags2 = self.flags2() & !(bits & 0x01F8);
+        self.set_word(0x10, flags2);
+    }
+
+    fn set_flags2_bit(&mut self, bit: u16, on: bool) {
+        let flags2 = if on { self.flags2() | bit } else { self.flags2() & !bit };
+        self.set_word(0x10, flags2);
+    }
+
+    pub fn set_interpreter(&mut self, number: u8, version: u8) {
+        self.set_byte(0x1E, number);
+        self.set_byte(0x1F, version);
+    }
+
+    pub fn set_screen_size(&mut self, lines: u8, chars: u8) {
+        self.set_byte(0x20, lines);
+        self.set_byte(0x21, chars);
+    }
+
+    pub fn set_screen_units(&mut self, width: u16, height: u16, font_width: u8, font_height: u8) {
+        self.set_word(0x22, width);
+        self.set_word(0x24, height);
+        match self.version() {
+            6 => {
+                self.set_byte(0x26, font_height);
+                self.set_byte(0x27, font_width);
+            },
+            _ => {
+                self.set_byte(0x26, font_width);
+                self.set_byte(0x27, font_height);
+            },
+        }
+    }
+
+    pub fn set_standard_revision(&mut self, major: u8, minor: u8) {
+        self.set_byte(0x32, major);
+        self.set_byte(0x33, minor);
+    }
+
+    pub fn set_mouse(&mut self, x: u16, y: u16) {
+        self.set_extension(1, x);
+        self.set_extension(2, y);
+    }
+
+    // like Flags 2, the interpreter can only clear bits the game set
+    pub fn refuse_flags3(&mut self, bits: u16) {
+        let flags3 = self.flags3() & !bits;
+        self.set_extension(4, flags3);
+    }
+
+    // writes past the end of the table the game provided are dropped
+    fn set_extension(&mut self, n: u16, val: u16) {
+        if n == 0 || n > self.extension_len() {
+            return;
+        }
+
+        let idx = self.extension_table() + n as usize * 2;
+        if let Some(data) = self.data.as_mut().get_mut(idx..idx + 2) {
+            data.copy_from_slice(&val.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags1_keeps_the_games_bits() {
+        let mut data = [0u8; 64];
+        data[0x00] = 3;
+        data[0x01] = 0x02; // time game
+
+        let mut header = ZHeader::new(&mut data[..]).unwrap();
+        header.set_flags1(0xFF);
+        assert_eq!(header.flags1(), 0x72);
+
+        header.set_flags1(0x00);
+        assert!(header.time_game());
+    }
+
+    #[test]
+    fn packed_addresses_depend_on_the_version() {
+        let mut data = [0u8; 64];
+        data[0x28..0x2A].copy_from_slice(&0x10u16.to_be_bytes());
+        data[0x2A..0x2C].copy_from_slice(&0x20u16.to_be_bytes());
+
+        let expected = [(3, 0x200, 0x200), (5, 0x400, 0x400), (7, 0x480, 0x500), (8, 0x800, 0x800)];
+        for (version, routine, string) in expected {
+            data[0x00] = version;
+            let header = ZHeader::new(&data[..]).unwrap();
+            assert_eq!(header.routine_address(0x100), routine);
+            assert_eq!(header.string_address(0x100), string);
+        }
+    }
+
+    #[test]
+    fn extension_table_is_bounded_by_its_length() {
+        let mut data = [0u8; 72];
+        data[0x00] = 5;
+        data[0x36..0x38].copy_from_slice(&64u16.to_be_bytes());
+        data[64..66].copy_from_slice(&2u16.to_be_bytes());
+
+        let mut header = ZHeader::new(&mut data[..]).unwrap();
+        header.set_mouse(10, 20);
+        assert_eq!(header.mouse(), (10, 20));
+        assert_eq!(header.flags3(), 0);
+    }
+}
@@ -4,6 +4,7 @@ use crate::{
         instr::*,
         operand::*,
     },
+    zerror::ZMachineError,
 };
 
 bitstruct! {
@@ -62,9 +63,15 @@ impl InstrTypeProvider for Opcode {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InstructionType {
+    Long, Short, Variable, ZeroOps, Extended
+}
+
 #[derive(Debug)]
-pub(crate) enum InstructionType {
-    Long, Short, Variable, ZeroOps
+pub(crate) struct DecodeError {
+    pub(crate) form: InstructionType,
+    pub(crate) error: ZMachineError,
 }
 
 #[derive(Debug)]
@@ -74,19 +81,15 @@ pub(crate) struct Instruction {
     pub(crate) ops: Vec<Operand>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone)]
 pub(crate) enum Address {
+    #[default]
     StackPointer,
     Local(u16),
     Global(u16),
-    Word(u16),
-    Byte(u16),
-}
-
-impl Default for Address {
-    fn default() -> Address {
-        Address::StackPointer
-    }
+    // byte addresses can reach past 64K in V6+
+    Word(usize),
+    Byte(usize),
 }
 
 impl Address {
@@ -104,9 +107,9 @@ impl Address {
         match self {
             Address::StackPointer => 0,
             Address::Local(v) => *v + 1,
-            Address::Global(v) => *v,
-            Address::Word(v) => *v,
-            Address::Byte(v) => *v,
+            Address::Global(v) => *v + 0x10,
+            Address::Word(v) => *v as u16,
+            Address::Byte(v) => *v as u16,
         }
     }
 }
@@ -129,12 +132,19 @@ impl Operand {
 }
 
 impl Instruction {
-    pub fn from_mem(mem: &[u8]) -> (Instruction, usize) {
-        let mut offset = 0 as usize;
+    pub fn from_mem(mem: &[u8]) -> Result<(Instruction, usize), DecodeError> {
+        let mut offset = 0usize;
         let op = Opcode::new(mem[offset]);
         let mut operands: Vec<Operand> = vec![];
 
-        match op.instr_type() {
+        // 0xBE would otherwise be a short form 0OP
+        let ty = if mem[0] == EXTENDED {
+            InstructionType::Extended
+        } else {
+            op.instr_type()
+        };
+
+        match ty {
             InstructionType::Long => {
                 let instr = LongInstruction::new(mem[offset]);
                 offset += 1;
@@ -155,11 +165,11 @@ impl Instruction {
                     operands.push(Operand::SmallConstant(val as u16));
                 }
 
-                (Instruction {
+                Ok((Instruction {
                     opcode: instr.opcode.value_of(),
                     ty: InstructionType::Long,
                     ops: operands,
-                }, offset)
+                }, offset))
             },
             InstructionType::ZeroOps |
             InstructionType::Short => {
@@ -167,50 +177,34 @@ impl Instruction {
                 offset += 1;
 
                 let arg_type = instr.arg_type.value_of();
-                if let Some((op, b)) = Instruction::extract_operand(&arg_type, &mem[offset..]) {
+                if let Some((op, b)) = Instruction::extract_operand(&arg_type, &mem[offset..], InstructionType::Short)? {
                     operands.push(op);
                     offset += b;
                 }
 
                 let mut instr_type = InstructionType::Short;
-                if operands.len() == 0 {
+                if operands.is_empty() {
                     instr_type = InstructionType::ZeroOps;
                 }
 
-                (Instruction {
+                Ok((Instruction {
                     opcode: instr.opcode.value_of(),
                     ty: instr_type,
                     ops: operands,
-                }, offset)
+                }, offset))
             },
             InstructionType::Variable => {
                 let raw = ZWord::from((mem[offset], mem[offset + 1]));
                 offset += 2;
                 let instr = VarInstruction::new(raw.into());
 
-                let arg1_type = instr.arg1_type.value_of() as u8;
-                if let Some((op, b)) = Instruction::extract_operand(&arg1_type, &mem[offset..]) {
-                    operands.push(op);
-                    offset += b;
-                }
-
-                let arg2_type = instr.arg2_type.value_of() as u8;
-                if let Some((op, b)) = Instruction::extract_operand(&arg2_type, &mem[offset..]) {
-                    operands.push(op);
-                    offset += b;
-                }
-
-                let arg3_type = instr.arg3_type.value_of() as u8;
-                if let Some((op, b)) = Instruction::extract_operand(&arg3_type, &mem[offset..]) {
-                    operands.push(op);
-                    offset += b;
-                }
-
-                let arg4_type = instr.arg4_type.value_of() as u8;
-                if let Some((op, b)) = Instruction::extract_operand(&arg4_type, &mem[offset..]) {
-                    operands.push(op);
-                    offset += b;
+                // call_vs2 and call_vn2 have a second types byte for up to 8 operands
+                let mut types = vec![mem[1]];
+                if mem[0] == CALL_VS2 || mem[0] == CALL_VN2 {
+                    types.push(mem[offset]);
+                    offset += 1;
                 }
+                offset += Instruction::extract_operands(&types, &mem[offset..], InstructionType::Variable, &mut operands)?;
 
                 let ty = if !instr.is_2op.is_set() {
                     InstructionType::Long
@@ -218,38 +212,110 @@ impl Instruction {
                     InstructionType::Variable
                 };
 
-                (Instruction {
+                Ok((Instruction {
                     opcode: instr.opcode.value_of() as u8,
                     ty,
                     ops: operands,
-                }, offset)
+                }, offset))
+            },
+            InstructionType::Extended => {
+                // the opcode gets a byte of its own, then types like the variable form
+                let opcode = mem[1];
+                offset += 3;
+                offset += Instruction::extract_operands(&mem[2..3], &mem[offset..], InstructionType::Extended, &mut operands)?;
+
+                Ok((Instruction {
+                    opcode,
+                    ty: InstructionType::Extended,
+                    ops: operands,
+                }, offset))
             }
         }
     }
 
-    fn extract_operand(ty: &u8, mem: &[u8]) -> Option<(Operand, usize)> {
+    // Each types byte holds four 2 bit types, first operand in the top bits.
+    // The first omitted operand ends the list.
+    fn extract_operands(types: &[u8], mem: &[u8], form: InstructionType, operands: &mut Vec<Operand>) -> Result<usize, DecodeError> {
+        let mut offset = 0;
+        for ty in types.iter().flat_map(|t| [t >> 6, (t >> 4) & 0x03, (t >> 2) & 0x03, t & 0x03]) {
+            match Instruction::extract_operand(&ty, &mem[offset..], form)? {
+                Some((op, b)) => {
+                    operands.push(op);
+                    offset += b;
+                },
+                None => break,
+            }
+        }
+
+        Ok(offset)
+    }
+
+    fn extract_operand(ty: &u8, mem: &[u8], form: InstructionType) -> Result<Option<(Operand, usize)>, DecodeError> {
         match *ty {
             LARGE_CONSTANT => {
                 let val: ZWord = (mem[0], mem[1]).into();
 
-                Some((Operand::LargeConstant(val.into()), 2))
+                Ok(Some((Operand::LargeConstant(val.into()), 2)))
             },
             SMALL_CONSTANT => {
                 let val: ZWord = (0, mem[0]).into();
 
-                Some((Operand::SmallConstant(val.into()), 1))
+                Ok(Some((Operand::SmallConstant(val.into()), 1)))
             },
             VARIABLE => {
                 let addr: ZWord = (0, mem[0]).into();
 
-                Some((Operand::Variable(Address::of(addr.into())), 1))
+                Ok(Some((Operand::Variable(Address::of(addr.into())), 1)))
             },
-            OMITTED => None,
-            v => {
-                println!("warn: unexpected operand type {}", v);
-
-                None
-            }
+            OMITTED => Ok(None),
+            v => Err(DecodeError { form, error: ZMachineError::BadOperandType(v) }),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(code: &[u8]) -> (Instruction, usize) {
+        let mut mem = [0u8; 24];
+        mem[..code.len()].copy_from_slice(code);
+        Instruction::from_mem(&mem).unwrap()
+    }
+
+    #[test]
+    fn extended_opcodes_have_their_own_byte() {
+        // save_undo -> sp
+        let (instr, len) = decode(&[0xBE, 0x09, 0xFF, 0x00]);
+        assert_eq!(instr.ty, InstructionType::Extended);
+        assert_eq!(instr.opcode, 9);
+        assert!(instr.ops.is_empty());
+        assert_eq!(len, 3);
+
+        // log_shift 1 2
+        let (instr, len) = decode(&[0xBE, 0x03, 0x5F, 0x01, 0x02]);
+        assert_eq!(instr.opcode, 3);
+        assert_eq!(instr.ops.len(), 2);
+        assert_eq!(len, 5);
+    }
+
+    #[test]
+    fn double_variable_calls_take_eight_operands() {
+        let (instr, len) = decode(&[0xEC, 0x15, 0x55, 0x12, 0x34, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(instr.ty, InstructionType::Variable);
+        assert_eq!(instr.opcode, 12);
+        let ops: Vec<u16> = instr.ops.iter().map(|op| op.value()).collect();
+        assert_eq!(ops, vec![0x1234, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(len, 12);
+    }
+
+    #[test]
+    fn variable_form_2ops_can_have_more_operands() {
+        // je 1 2 3 4
+        let (instr, len) = decode(&[0xC1, 0x55, 1, 2, 3, 4]);
+        assert_eq!(instr.ty, InstructionType::Long);
+        assert_eq!(instr.opcode, 1);
+        assert_eq!(instr.ops.len(), 4);
+        assert_eq!(len, 6);
+    }
+}
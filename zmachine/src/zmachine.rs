@@ -4,62 +4,104 @@ use std::io::prelude::*;
 use std::io::BufReader;
 use std::fs::File;
 use std::cell::RefCell;
-use std::convert::TryInto;
+use std::fmt;
 
 use crate::zinst::{Instruction, InstructionType, Operand, Address, BranchLabel, Offset};
-use crate::bits::ZWord;
 use crate::zstr::{ZCharWord, ZChar};
-
-use std::time::SystemTime;
-use rand::{rngs::{StdRng}, Rng, SeedableRng, distributions::{Uniform}};
-
-#[derive(Debug)]
-struct ZRng<T: SeedableRng + Rng> {
-    seed: u64,
-    runs: usize,
-    rng: T
+use crate::quetzal::{QuetzalSave, QuetzalFrame, QuetzalError};
+use crate::zscreen::{ZScreen, ZWindow};
+use crate::zstream::ZStreams;
+use crate::zsound::{ZSound, ZSoundHost};
+use crate::ztimer::{ZClock, ZTimer};
+use crate::zundo::ZUndo;
+use crate::zerror::ZMachineError;
+use crate::zheader::ZHeader;
+use crate::zprofile::InterpreterProfile;
+
+use std::time::{Duration, SystemTime};
+use rand::{rngs::{StdRng}, Rng, RngCore, SeedableRng, distributions::{Uniform}};
+
+// random follows 2.4: the game can reseed at any time, and a small negative
+// seed switches to a counting sequence that makes testing predictable.
+struct ZRng {
+    rng: Box<dyn RngCore>,
+    seed: Option<u64>,
+    game_seeded: bool,
+    sequence: Option<(u16, u16)>,
 }
 
-impl<T: SeedableRng + Rng> Default for ZRng<T> {
+impl Default for ZRng {
     fn default() -> Self {
-        ZRng::new()
+        ZRng {
+            rng: Box::new(StdRng::seed_from_u64(ZRng::time_seed())),
+            seed: None,
+            game_seeded: false,
+            sequence: None,
+        }
     }
 }
 
-impl<T> ZRng<T>
-    where T: SeedableRng + Rng {
-    fn new() -> Self {
-        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("can get system time");
-        let seed = now.as_millis() as u64;
-
-        let rng = T::seed_from_u64(seed);
+impl fmt::Debug for ZRng {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ZRng")
+            .field("seed", &self.seed)
+            .field("game_seeded", &self.game_seeded)
+            .field("sequence", &self.sequence)
+            .finish()
+    }
+}
 
-        ZRng { seed, runs: 0, rng }
+impl ZRng {
+    fn time_seed() -> u64 {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        now.as_millis() as u64
     }
 
-    fn seed_with(&mut self, val: u64) {
-        self.rng = T::seed_from_u64(val);
-        self.seed = val;
-        self.runs = 0;
+    // a seed from the host sticks, even when the game asks for a random one
+    fn seed_with(&mut self, seed: u64) {
+        self.rng = Box::new(StdRng::seed_from_u64(seed));
+        self.seed = Some(seed);
+        self.game_seeded = false;
+        self.sequence = None;
     }
 
-    fn seed(&mut self) {
-        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).expect("can get system time");
-        let seed = now.as_millis() as u64;
+    fn set_rng(&mut self, rng: Box<dyn RngCore>) {
+        self.rng = rng;
+        self.seed = None;
+        self.game_seeded = false;
+        self.sequence = None;
+    }
 
-        self.rng = T::seed_from_u64(seed);
-        self.seed = seed;
-        self.runs = 0;
+    fn reseed(&mut self, seed: u16) {
+        self.sequence = None;
+        if seed == 0 {
+            // back to whatever the host set up, or the clock
+            if let Some(seed) = self.seed {
+                self.rng = Box::new(StdRng::seed_from_u64(seed));
+            } else if self.game_seeded {
+                self.rng = Box::new(StdRng::seed_from_u64(ZRng::time_seed()));
+            }
+            self.game_seeded = false;
+        } else if seed < 1000 {
+            self.sequence = Some((seed, 0));
+        } else {
+            self.rng = Box::new(StdRng::seed_from_u64(seed as u64));
+            self.game_seeded = true;
+        }
     }
 
-    fn range(&mut self, range: std::ops::Range<u16>) -> u16 {
-        let dist = Uniform::from(range);
-        self.rng.sample(dist)
+    // uniform over 1..=range
+    fn next(&mut self, range: u16) -> u16 {
+        match self.sequence.as_mut() {
+            Some((limit, last)) => {
+                *last = *last % *limit + 1;
+                (*last - 1) % range + 1
+            },
+            None => self.rng.sample(Uniform::new_inclusive(1, range)),
+        }
     }
 }
 
-type ZStdRng = ZRng<StdRng>;
-
 #[derive(Debug)]
 struct BranchOffset {
     target: bool,
@@ -80,100 +122,202 @@ impl BranchOffset {
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct StackFrame {
     locals: Vec<u16>,
     stack: Vec<u16>,
-    ret_addr: Address,
+    ret_addr: Option<Address>,
     pc: usize,
+    args: usize,
+    // a timed input routine, its result goes back to the waiting read
+    interrupt: bool,
+}
+
+#[derive(Debug, Clone)]
+enum InputRequest {
+    // V5+ store the key that ended the line
+    Line { text: u16, parse: u16, store: Option<Address> },
+    Char(Address),
+}
+
+
+// where an undo state picks up from
+#[derive(Debug)]
+enum UndoPoint {
+    // the store of a save_undo, which gets 2
+    SaveUndo,
+    // a host checkpoint, maybe waiting for input
+    Checkpoint(Option<InputRequest>),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SaveRequest {
+    Save,
+    Restore,
+}
 
 #[derive(Default, Debug)]
 pub struct ZMachine {
     memory: RefCell<ZMemory>,
+    story: Vec<u8>,
     stack: RefCell<Vec<StackFrame>>,
-    input_buffer: RefCell<Option<(u16, u16)>>,
-    rng: RefCell<ZStdRng>,
+    input: RefCell<Option<InputRequest>>,
+    timer: RefCell<ZTimer>,
+    undo: RefCell<ZUndo<(Vec<StackFrame>, UndoPoint)>>,
+    save_request: RefCell<Option<SaveRequest>>,
+    screen: RefCell<ZScreen>,
+    streams: RefCell<ZStreams>,
+    sound: RefCell<ZSoundHost>,
+    profile: RefCell<InterpreterProfile>,
+    rng: RefCell<ZRng>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusProgress {
+    Score { score: i16, turns: u16 },
+    Time { hours: u16, minutes: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusLine {
+    pub location: String,
+    pub progress: StatusProgress,
 }
 
 pub enum ZMachineExecResult {
     NeedInput,
+    // V4+ read_char wants a single key. Answer with `send_char`.
+    NeedChar,
+    // As above, but after this many tenths of a second without an answer the
+    // host calls `send_timeout` and runs the machine again.
+    NeedTimedInput(u16),
+    NeedTimedChar(u16),
+    // V3 only: redraw the status line before going on
+    ShowStatus(StatusLine),
+    // The game wants to save. Persist the Quetzal bytes and report back with `send_save_result`.
+    Save(Vec<u8>),
+    // The game wants to restore. Answer with `send_restore`.
+    Restore,
     Next,
-    Exit
+    Exit,
+    Error(ExecError),
 }
 
-struct ZLexicalAnalyzer {
-    tb_addr: u16,
-    pb_addr: u16,
+#[derive(Debug, Clone)]
+pub struct ExecError {
+    pub pc: usize,
+    pub bytes: Vec<u8>,
+    pub form: InstructionType,
+    pub error: ZMachineError,
 }
 
-#[derive(Debug)]
-struct ZDictEntry {
-    chars: (ZCharWord, ZCharWord)
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({:?} form) at {:05x}:", self.error, self.form, self.pc)?;
+        for b in self.bytes.iter() {
+            write!(f, " {:02x}", b)?;
+        }
+        Ok(())
+    }
 }
 
-impl ZDictEntry {
-
-    fn as_u32(&self) -> u32 {
-        let (ref hi, ref low) = self.chars;
-        let hi = hi.get().to_be_bytes();
-        let lo = low.get().to_be_bytes();
-        let slice = &[hi, lo].concat();
-
-        u32::from_be_bytes(slice.as_slice().try_into().unwrap())
+impl std::error::Error for ExecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
     }
+}
 
-    fn from_slice(slice: &[u8]) -> ZDictEntry {
-        let mut lo = ZCharWord::new(0);
-        let mut hi = ZCharWord::new(0);
+#[derive(Debug, PartialEq)]
+pub enum LoadWarning {
+    ChecksumMismatch { expected: u16, actual: u16 },
+}
 
-        let mut chars: Vec<ZChar> = Vec::new();
-        for c in slice.iter().take(6) {
-            chars.extend(ZChar::encode(c.to_ascii_lowercase()));
+impl fmt::Display for LoadWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadWarning::ChecksumMismatch { expected, actual } => {
+                write!(f, "story checksum is {:04x} but the header says {:04x}, the file may be corrupt", actual, expected)
+            },
         }
+    }
+}
 
-        if let Some(c) = chars.get(0) {
-            hi.first.set(*c.value() as u16);
-        } else {
-            hi.first.set(5);
-        }
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    TooShort { length: usize, needed: usize },
+    UnsupportedVersion(u8),
+    BadHeader(ZMachineError),
+}
 
-        if let Some(c) = chars.get(1) {
-            hi.second.set(*c.value() as u16);
-        } else {
-            hi.second.set(5);
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::TooShort { length, needed } => {
+                write!(f, "story is {} bytes long but its header needs at least {}", length, needed)
+            },
+            LoadError::UnsupportedVersion(v) => write!(f, "unsupported story version {}", v),
+            LoadError::BadHeader(e) => write!(f, "bad story header: {}", e),
         }
+    }
+}
 
-        if let Some(c) = chars.get(2) {
-            hi.third.set(*c.value() as u16);
-        } else {
-            hi.third.set(5);
-        }
+impl std::error::Error for LoadError {}
 
-        if let Some(c) = chars.get(3) {
-            lo.first.set(*c.value() as u16);
-        } else {
-            lo.first.set(5);
-        }
+impl From<std::io::Error> for LoadError {
+    fn from(e: std::io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
 
-        if let Some(c) = chars.get(4) {
-            lo.second.set(*c.value() as u16);
-        } else {
-            lo.second.set(5);
-        }
+struct ZLexicalAnalyzer {
+    tb_addr: u16,
+    pb_addr: u16,
+    dictionary: Option<u16>,
+    skip_unknown: bool,
+}
 
-        if let Some(c) = chars.get(5) {
-            lo.third.set(*c.value() as u16);
-        } else {
-            lo.third.set(5);
-        }
+// Dictionary words are cut down to 6 z-chars in V1-3 and 9 after, padded
+// with 5s, with the top bit set on the last word.
+#[derive(Debug)]
+struct ZDictEntry {
+    words: Vec<ZCharWord>,
+}
+
+impl ZDictEntry {
 
-        lo.last_flag.set(1);
+    fn bytes(&self) -> Vec<u8> {
+        self.words.iter()
+            .flat_map(|word| word.get().to_be_bytes())
+            .collect()
+    }
 
-        let ret = ZDictEntry { chars: (hi, lo) };
+    fn from_slice(slice: &[u8], version: u8) -> ZDictEntry {
+        let n_chars = if version <= 3 { 6 } else { 9 };
+
+        let mut chars: Vec<u8> = slice.iter()
+            .flat_map(|c| ZChar::encode(c.to_ascii_lowercase()))
+            .map(u8::from)
+            .take(n_chars)
+            .collect();
+        chars.resize(n_chars, 5);
+
+        let mut words: Vec<ZCharWord> = chars.chunks(3)
+            .map(|c| {
+                let mut word = ZCharWord::new(0);
+                word.first.set(c[0] as u16);
+                word.second.set(c[1] as u16);
+                word.third.set(c[2] as u16);
+                word
+            })
+            .collect();
+
+        if let Some(last) = words.last_mut() {
+            last.last_flag.set(1);
+        }
 
-        ret
+        ZDictEntry { words }
     }
 }
 
@@ -184,83 +328,79 @@ struct ZLexWord {
     pub tb_idx: u8
 }
 
-impl<'a> ZLexWord {
+impl ZLexWord {
     pub fn new(dict_addr: u16, len: u8, tb_idx: u8) -> ZLexWord {
-        let ret = ZLexWord { dict_addr, len, tb_idx };
-        ret
+        ZLexWord { dict_addr, len, tb_idx }
     }
 }
 
 impl ZLexicalAnalyzer {
     pub fn new(tb_addr: u16, pb_addr: u16) -> ZLexicalAnalyzer {
-        ZLexicalAnalyzer { tb_addr, pb_addr }
+        ZLexicalAnalyzer { tb_addr, pb_addr, dictionary: None, skip_unknown: false }
     }
 
-    fn run(&self, mem: &mut ZMemory) {
-        let dictionary = mem.dictionary();
-        let separators = dictionary.separators();
-
-        let text = mem.slice(self.tb_addr as usize + 1);
-        let mut words: Vec<ZLexWord> = Vec::new();
-
-        let max_words = mem.read_byte(self.pb_addr as usize);
-        let mut idx = 0usize;
-        for (i, ch) in text.iter()
-            .enumerate() {
-
-            if i >= max_words as usize {
-                break;
-            }
-
-            if separators.contains(ch) {
-                let word = &text[idx..i];
-
-                let dict_entry = ZDictEntry::from_slice(word).as_u32();
-                let mut dict_addr = 0;
-                if let Some(addr) = dictionary.lookup(&dict_entry) {
-                    dict_addr = addr;
-                }
-                words.push(ZLexWord::new(dict_addr as u16, word.len() as u8, idx as u8));
+    // tokenise can look words up in a dictionary of the game's own, and
+    // leave the parse entries for words it doesn't know as they were
+    pub fn with_dictionary(self, dictionary: u16, skip_unknown: bool) -> ZLexicalAnalyzer {
+        ZLexicalAnalyzer {
+            dictionary: if dictionary != 0 { Some(dictionary) } else { None },
+            skip_unknown,
+            ..self
+        }
+    }
 
-                let sep_word = &text[i..i + 1];
-                let dict_entry = ZDictEntry::from_slice(sep_word).as_u32();
-                let mut dict_addr = 0;
-                if let Some(addr) = dictionary.lookup(&dict_entry) {
-                    dict_addr = addr;
-                }
-                words.push(ZLexWord::new(dict_addr as u16, sep_word.len() as u8, i as u8));
+    fn run(&self, mem: &mut ZMemory) -> Result<(), ZMachineError> {
+        let version = mem.header().version();
+        let dictionary = match self.dictionary {
+            Some(addr) => mem.dictionary_at(addr as usize)?,
+            None => mem.dictionary()?,
+        };
+        let separators = dictionary.separators();
 
-                idx = i + 1;
+        let (start, text) = mem.read_text(self.tb_addr)?;
 
-            } else if *ch == ' ' as u8 {
-                let word = &text[idx..i]; // don't include the space
-                let dict_entry = ZDictEntry::from_slice(word).as_u32();
-                let mut dict_addr = 0;
-                if let Some(addr) = dictionary.lookup(&dict_entry) {
-                    dict_addr = addr;
+        // spaces split words, separators split them and are words themselves
+        let mut words: Vec<(usize, &[u8])> = Vec::new();
+        let mut word_start: Option<usize> = None;
+        for (i, ch) in text.iter().enumerate() {
+            if *ch == b' ' || separators.contains(ch) {
+                if let Some(idx) = word_start.take() {
+                    words.push((idx, &text[idx..i]));
                 }
-                words.push(ZLexWord::new(dict_addr as u16, word.len() as u8, idx as u8));
-                idx = i + 1;
-            } else if *ch == 0 || *ch == '\n' as u8 {
-                let word = &text[idx..i];
-                let dict_entry = ZDictEntry::from_slice(word).as_u32();
-                let mut dict_addr = 0;
-                if let Some(addr) = dictionary.lookup(&dict_entry) {
-                    dict_addr = addr;
+                if *ch != b' ' {
+                    words.push((i, &text[i..i + 1]));
                 }
-                words.push(ZLexWord::new(dict_addr as u16, word.len() as u8, idx as u8));
-
-                break;
+            } else if word_start.is_none() {
+                word_start = Some(i);
             }
         }
+        if let Some(idx) = word_start {
+            words.push((idx, &text[idx..]));
+        }
 
-        mem.set_byte(self.pb_addr as usize + 1, words.len() as u8);
+        let max_words = mem.read_byte(self.pb_addr as usize)? as usize;
+        let words: Vec<ZLexWord> = words.into_iter()
+            .take(max_words)
+            .map(|(idx, word)| {
+                let dict_entry = ZDictEntry::from_slice(word, version).bytes();
+                let dict_addr = dictionary.lookup(&dict_entry).unwrap_or(0);
+                // positions count from the start of the buffer
+                ZLexWord::new(dict_addr as u16, word.len() as u8, (start - self.tb_addr as usize + idx) as u8)
+            })
+            .collect();
+
+        mem.set_byte(self.pb_addr as usize + 1, words.len() as u8)?;
         let idx = self.pb_addr as usize + 2;
         for (i, word) in (0..words.len() * 4).step_by(4).zip(words.iter()) {
-            mem.set_word(idx + i, word.dict_addr.into());
-            mem.set_byte(idx + i + 2, word.len);
-            mem.set_byte(idx + i + 3, word.tb_idx + 1); // skip the size byte
+            if word.dict_addr == 0 && self.skip_unknown {
+                continue;
+            }
+            mem.set_word(idx + i, word.dict_addr.into())?;
+            mem.set_byte(idx + i + 2, word.len)?;
+            mem.set_byte(idx + i + 3, word.tb_idx)?;
         }
+
+        Ok(())
     }
 
 }
@@ -270,339 +410,1073 @@ impl ZMachine {
         ZMachine::default()
     }
 
-    fn reset(&mut self, buf: Vec<u8>) {
-        let mut mem = self.memory.borrow_mut();
-        mem.reset(buf);
+    fn reset(&mut self, buf: Vec<u8>) -> Result<(), ZMachineError> {
+        self.memory.borrow_mut().reset(buf.clone())?;
+        self.story = buf;
+        self.undo.borrow_mut().clear();
 
-        let header = mem.header();
+        self.reset_state();
+        Ok(())
+    }
+
+    fn reset_state(&self) {
+        self.input.replace(None);
+        self.timer.borrow_mut().stop();
+        self.save_request.replace(None);
+        let width = self.profile.borrow().screen_width;
+        self.screen.replace(ZScreen::new(width as usize));
+        self.write_profile();
+
+        let mem = self.memory.borrow();
         self.stack.replace(vec![StackFrame::default()]);
 
         let mut stack = self.stack.borrow_mut();
         let current = &mut stack[0];
 
-        let pc: ZWord = (header[6], header[7]).into();
-        current.pc = u16::from(pc) as usize;
+        current.pc = mem.header().initial_pc();
     }
 
-    pub fn load(&mut self, filename: &str) -> std::io::Result<()> {
-        let f = File::open(filename)?;
-        let mut reader = BufReader::new(f);
-        let mut buf: Vec<u8> = Vec::new();
-        reader.read_to_end(&mut buf)?;
+    // Takes effect straight away if a story is loaded, and is written again
+    // on every restart and restore.
+    pub fn set_profile(&self, profile: InterpreterProfile) {
+        self.profile.replace(profile);
+        self.write_profile();
+    }
 
-        self.reset(buf);
+    fn write_profile(&self) {
+        if self.story.is_empty() {
+            return;
+        }
 
-        Ok(())
+        let mut mem = self.memory.borrow_mut();
+        self.profile.borrow().apply(&mut mem.header_mut());
     }
 
-    fn fetch_next_instr(&self) -> Instruction {
-        let mut stack = self.stack.borrow_mut();
-        let mem = self.memory.borrow();
-
-        let idx = stack.len() - 1;
-        let frame = &mut stack[idx];
+    pub fn restart(&self) {
+        if self.story.is_empty() {
+            return;
+        }
 
-        let (instr, offset) = Instruction::from_mem(mem.slice(frame.pc));
-        frame.pc += offset;
+        self.memory.borrow_mut().restore_dynamic(&self.story);
+        self.undo.borrow_mut().clear();
 
-        instr
+        self.reset_state();
     }
 
-    fn store_text_buffer(&self, tb: u16, pb: u16) {
-        let mut buffer = self.input_buffer.borrow_mut();
-        buffer.replace((tb, pb));
+    pub fn load(&mut self, filename: &str) -> Result<Vec<LoadWarning>, LoadError> {
+        let f = File::open(filename)?;
+        self.load_reader(BufReader::new(f))
     }
 
-    fn get_pc(&self) -> usize {
-        let stack = self.stack.borrow();
-        let idx = stack.len() - 1;
+    pub fn load_reader<R: Read>(&mut self, mut reader: R) -> Result<Vec<LoadWarning>, LoadError> {
+        let mut buf: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut buf)?;
 
-        stack[idx].pc
+        self.load_bytes(buf)
     }
 
-    fn read_store(&self, pc: &mut usize) -> Address {
-        let mem = self.memory.borrow();
-        let addr = Address::of(mem.read_byte(*pc) as u16);
-        *pc += 1;
+    pub fn load_bytes<B: Into<Vec<u8>>>(&mut self, bytes: B) -> Result<Vec<LoadWarning>, LoadError> {
+        let mut buf = bytes.into();
+        let header = match ZHeader::new(&buf[..]) {
+            Some(header) => header,
+            None => return Err(LoadError::TooShort { length: buf.len(), needed: 64 }),
+        };
 
-        addr
-    }
+        let version = header.version();
+        if !(1..=8).contains(&version) {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+
+        // everything the header says is there has to actually be there
+        let file_length = ZMachine::file_length(&buf);
+        let needed = file_length.max(header.high_memory()).max(header.static_memory());
+        if buf.len() < needed {
+            return Err(LoadError::TooShort { length: buf.len(), needed });
+        }
 
-    pub fn send_input(&self, input: &str) {
-        if let Some((text_buffer_addr, parse_buffer_addr)) = *self.input_buffer.borrow() {
-            let mut mem = self.memory.borrow_mut();
-            mem.write_text(text_buffer_addr, &input[..]);
+        // anything past the declared length is padding
+        buf.truncate(file_length);
+        self.reset(buf).map_err(LoadError::BadHeader)?;
 
-            let analyzer = ZLexicalAnalyzer::new(text_buffer_addr, parse_buffer_addr);
-            analyzer.run(&mut mem);
+        let mut warnings = Vec::new();
+        let (expected, actual) = (self.header_checksum(), self.checksum());
+        if expected != actual {
+            warnings.push(LoadWarning::ChecksumMismatch { expected, actual });
         }
 
-        self.input_buffer.replace(None);
+        Ok(warnings)
     }
 
-    pub fn exec<W: Write>(&self, output: &mut W) -> ZMachineExecResult {
-        loop {
-            match self.exec_one(output) {
-                ZMachineExecResult::Next => continue,
-                result => return result
-            }
+    fn file_length(story: &[u8]) -> usize {
+        // some very early stories leave the length blank
+        match ZHeader::new(story).map(|header| header.file_length()) {
+            Some(0) | None => story.len(),
+            Some(len) => len,
         }
     }
 
-    fn exec_one<W: Write>(&self, output: &mut W) -> ZMachineExecResult {
-        let instr = self.fetch_next_instr();
+    fn header_checksum(&self) -> u16 {
+        ZHeader::new(&self.story[..]).map(|header| header.checksum()).unwrap_or(0)
+    }
 
-        let mut pc = self.get_pc();
+    // A copy of the header as it stands in memory, including the extension
+    // table. None until a story is loaded.
+    pub fn header(&self) -> Option<ZHeader<Vec<u8>>> {
+        ZHeader::new(self.memory.borrow().dynamic().to_vec())
+    }
 
-        match instr.ty {
-            InstructionType::Long => {
-                match instr.opcode {
-                    1 => { // jump equal
-                        let test = self.get_value(&instr.ops[0]) as i16;
-                        let offset = self.read_offset(&mut pc);
-                        let matches = instr.ops[1..].iter().any(|op| self.get_value(op) as i16 == test);
-                        if matches == *offset.target() {
-                            self.branch(offset, &mut pc);
-                        }
-                    }
-                    2 => { // jump less than
-                        let lhs = self.get_value(&instr.ops[0]) as i16;
-                        let rhs = self.get_value(&instr.ops[1]) as i16;
-                        let cond = lhs < rhs;
+    // Lets the host fill in the fields an interpreter is responsible for.
+    pub fn update_header<F: FnOnce(&mut ZHeader<&mut [u8]>)>(&self, f: F) {
+        if self.story.is_empty() {
+            return;
+        }
 
-                        let offset = self.read_offset(&mut pc);
-                        if cond == *offset.target() {
-                            self.branch(offset, &mut pc);
-                        }
-                    },
-                    3 => { // jump greater than
-                        let lhs = self.get_value(&instr.ops[0]) as i16;
-                        let rhs = self.get_value(&instr.ops[1]) as i16;
-                        let cond = lhs > rhs;
+        f(&mut self.memory.borrow_mut().header_mut());
+    }
 
-                        let offset = self.read_offset(&mut pc);
-                        if cond == *offset.target() {
-                            self.branch(offset, &mut pc);
-                        }
-                    },
-                    4 => { // dec check
-                        let var_num = self.get_value(&instr.ops[0]);
-                        let var_addr = Address::of(var_num);
-                        let op = Operand::Variable(var_addr);
+    // Sum of every byte after the header up to the length the header gives,
+    // always taken over the original story rather than live memory.
+    pub fn checksum(&self) -> u16 {
+        let end = ZMachine::file_length(&self.story).min(self.story.len());
 
-                        let var = self.get_value(&op) as i16;
-                        let val = self.get_value(&instr.ops[1]) as i16;
+        self.story.get(0x40..end)
+            .unwrap_or(&[])
+            .iter()
+            .fold(0u16, |sum, b| sum.wrapping_add(*b as u16))
+    }
 
-                        let var = var.wrapping_sub(1);
-                        let cond = var < val;
+    pub fn upper_window(&self) -> Vec<String> {
+        self.screen.borrow().upper_lines()
+    }
 
-                        let addr = Address::of(var_num);
-                        self.store(var as u16, &addr);
+    pub fn set_screen_output(&self, on: bool) {
+        self.streams.borrow_mut().select_screen(on);
+    }
 
-                        let offset = self.read_offset(&mut pc);
-                        if cond == offset.target {
-                            self.branch(offset, &mut pc);
-                        }
-                    },
-                    5 => { // inc check
-                        let var_num = self.get_value(&instr.ops[0]);
-                        let var_addr = Address::of(var_num);
-                        let op = Operand::Variable(var_addr);
+    // Attaching a transcript turns transcripting on, the same as the game's SCRIPT.
+    pub fn set_transcript(&self, writer: Option<Box<dyn Write>>) {
+        let on = writer.is_some();
+        self.streams.borrow_mut().set_transcript(writer);
+        self.set_transcripting(on);
+    }
 
-                        let var = self.get_value(&op) as i16;
-                        let val = self.get_value(&instr.ops[1]) as i16;
+    pub fn set_command_log(&self, writer: Option<Box<dyn Write>>) {
+        self.streams.borrow_mut().set_commands(writer);
+    }
 
-                        let var = var.wrapping_add(1);
-                        let cond = var > val;
+    // Fixes the sequence of random numbers, so a session can be replayed exactly.
+    pub fn seed_random(&self, seed: u64) {
+        self.rng.borrow_mut().seed_with(seed);
+    }
 
-                        let addr = Address::of(var_num);
-                        self.store(var as u16, &addr);
+    pub fn set_random(&self, rng: Box<dyn RngCore>) {
+        self.rng.borrow_mut().set_rng(rng);
+    }
 
-                        let offset = self.read_offset(&mut pc);
-                        if cond == offset.target {
-                            self.branch(offset, &mut pc);
-                        }
-                    },
-                    6 => { //jump in
-                        let test_obj = self.get_value(&instr.ops[0]) as u8;
-                        let test_parent = self.get_value(&instr.ops[1]) as u8;
-                        let parent = {
-                            let mem = self.memory.borrow();
-                            mem.get_object_parent(test_obj)
-                        };
-                        let cond = parent == test_parent;
+    pub fn set_sound(&self, backend: Option<Box<dyn ZSound>>) {
+        self.sound.borrow_mut().set_backend(backend);
+    }
 
-                        let offset = self.read_offset(&mut pc);
-                        if cond == offset.target {
-                            self.branch(offset, &mut pc);
-                        }
-                    }
-                    7 => { // test
-                        let bm = self.get_value(&instr.ops[0]);
-                        let flags = self.get_value(&instr.ops[1]);
-                        let cond = bm & flags == flags;
+    // The host calls this when a sound it was asked to start has finished
+    // playing, so the game's completion routine gets a chance to run.
+    pub fn sound_finished(&self) {
+        self.sound.borrow_mut().finished();
+    }
 
-                        let offset = self.read_offset(&mut pc);
-                        if cond == offset.target {
-                            self.branch(offset, &mut pc);
-                        }
-                    },
-                    8 => { // or
-                        let store = self.read_store(&mut pc);
-                        let lhs = self.get_value(&instr.ops[0]);
-                        let rhs = self.get_value(&instr.ops[1]);
+    pub fn set_clock(&self, clock: Box<dyn ZClock>) {
+        self.timer.borrow_mut().set_clock(clock);
+    }
 
-                        let result = lhs | rhs;
+    // How long the host has left to wait for timed input before it should
+    // call `send_timeout`.
+    pub fn time_left(&self) -> Option<Duration> {
+        self.timer.borrow().time_left()
+    }
 
-                        self.store(result, &store);
-                    },
-                    9 => { // AND
-                        let store = self.read_store(&mut pc);
-                        let lhs = self.get_value(&instr.ops[0]);
-                        let rhs = self.get_value(&instr.ops[1]);
+    // How many undo states are kept, shared between the game's save_undo and
+    // `checkpoint`. 0 turns undo off.
+    pub fn set_undo_depth(&self, depth: usize) {
+        self.undo.borrow_mut().set_depth(depth);
+    }
 
-                        let result = lhs & rhs;
+    // Remembers the current state for `undo`, so a host can offer undo to
+    // games that have none of their own. Best called while waiting for input.
+    pub fn checkpoint(&self) {
+        if self.story.is_empty() {
+            return;
+        }
 
-                        self.store(result, &store);
-                    },
-                    10 => { // test_attr
-                        let obj_num = self.get_value(&instr.ops[0]);
-                        let attr = self.get_value(&instr.ops[1]);
-                        let offset = self.read_offset(&mut pc);
+        let point = UndoPoint::Checkpoint(self.input.borrow().clone());
+        self.push_undo(self.stack.borrow().clone(), point);
+    }
 
-                        let attr = {
-                            let mem = self.memory.borrow();
-                            mem.test_attr(obj_num as u8, attr as u8)
-                        };
+    // Goes back to the last checkpoint. Anything the game saved with
+    // save_undo since then is dropped.
+    pub fn undo(&self) -> bool {
+        if self.story.is_empty() {
+            return false;
+        }
 
-                        if attr == offset.target {
-                            self.branch(offset, &mut pc);
-                        }
-                    },
-                    11 => { // set attr
-                        let obj_num = self.get_value(&instr.ops[0]) as u8;
-                        let attr = self.get_value(&instr.ops[1]) as u8;
-                        let mut mem = self.memory.borrow_mut();
-                        mem.set_attr(obj_num, attr);
-                    },
-                    12 => { // clear attr
-                        let obj_num = self.get_value(&instr.ops[0]) as u8;
-                        let attr = self.get_value(&instr.ops[1]) as u8;
-                        let mut mem = self.memory.borrow_mut();
-                        mem.clear_attr(obj_num, attr);
-                    },
-                    13 => { // store
-                        let var_num = self.get_value(&instr.ops[0]);
-                        let addr = Address::of(var_num);
-                        let op = Operand::Variable(Address::of(var_num));
-                        let val = self.get_value(&instr.ops[1]);
+        while let Some(((stack, point), memory)) = self.undo.borrow_mut().pop() {
+            if let UndoPoint::Checkpoint(input) = point {
+                self.memory.borrow_mut().restore_dynamic(&memory);
+                self.stack.replace(stack);
+                self.input.replace(input);
+                self.timer.borrow_mut().stop();
+                return true;
+            }
+        }
 
-                        let _ = self.get_value(&op);
-                        self.store(val, &addr);
-                    },
-                    14 => { // insert_obj
-                        let obj_num = self.get_value(&instr.ops[0]) as u8;
-                        let new_parent = self.get_value(&instr.ops[1]) as u8;
+        false
+    }
 
-                        let mut mem = self.memory.borrow_mut();
-                        mem.insert_object(obj_num, new_parent);
-                    }
-                    15 => { // loadw
-                        let store = self.read_store(&mut pc);
+    // Commands are read from here in place of the keyboard until it runs out.
+    pub fn set_command_source(&self, reader: Option<Box<dyn BufRead>>) {
+        self.streams.borrow_mut().set_replay(reader);
+    }
 
-                        let addr = self.get_value(&instr.ops[0]);
-                        let idx = self.get_value(&instr.ops[1]);
-                        let addr = Address::Word(addr + (2 * idx));
+    // stream 2 lives in flags 2 bit 0 so games can flip it directly
+    fn transcripting(&self) -> bool {
+        self.memory.borrow().header().transcripting()
+    }
 
-                        let val = self.get_value(&Operand::Variable(addr));
-                        self.store(val, &store);
-                    },
-                    16 => { // loadb
-                        let addr = self.get_value(&instr.ops[0]);
-                        let idx = self.get_value(&instr.ops[1]);
-                        let store = self.read_store(&mut pc);
-                        let addr = Address::Byte(addr + idx);
+    fn set_transcripting(&self, on: bool) {
+        if self.story.is_empty() {
+            return;
+        }
 
-                        let val = self.get_value(&Operand::Variable(addr));
-                        self.store(val, &store);
-                    },
-                    17 => { //get prop
-                        let obj = self.get_value(&instr.ops[0]) as u8;
-                        let property = self.get_value(&instr.ops[1]) as u8;
-                        let prop = {
-                            let mem = self.memory.borrow();
-                            mem.get_prop(obj, property)
-                        };
+        self.memory.borrow_mut().header_mut().set_transcripting(on);
+    }
 
-                        let p: u16 = prop.into();
-                        let store = self.read_store(&mut pc);
-                        self.store(p, &store);
-                    },
-                    18 => { //get prop addr
-                        let obj = self.get_value(&instr.ops[0]) as u8;
-                        let prop_num = self.get_value(&instr.ops[1]) as u8;
-                        let addr = {
-                            let mem = self.memory.borrow();
-                            mem.get_prop_addr(obj, prop_num)
-                        };
+    fn select_stream(&self, stream: i16, table: Option<u16>) -> Result<(), ZMachineError> {
+        let mut streams = self.streams.borrow_mut();
+        match stream {
+            1 => streams.select_screen(true),
+            -1 => streams.select_screen(false),
+            2 | -2 => {
+                drop(streams);
+                self.set_transcripting(stream > 0);
+            },
+            3 => if let Some(table) = table {
+                streams.open_table(table as usize);
+            },
+            -3 => {
+                if let Some((addr, text)) = streams.close_table() {
+                    let mut mem = self.memory.borrow_mut();
+                    mem.set_word(addr, (text.len() as u16).into())?;
+                    for (i, b) in text.iter().enumerate() {
+                        mem.set_byte(addr + 2 + i, *b)?;
+                    }
+                }
+            },
+            4 => streams.select_commands(true),
+            -4 => streams.select_commands(false),
+            _ => {},
+        }
 
-                        let store = self.read_store(&mut pc);
-                        self.store(addr, &store);
-                    },
-                    19 => { // get next prop
-                        let obj = self.get_value(&instr.ops[0]) as u8;
-                        let prop_num = self.get_value(&instr.ops[1]) as u8;
-                        let next_prop = {
-                            let mem = self.memory.borrow();
-                            mem.get_next_prop(obj, prop_num)
-                        };
+        Ok(())
+    }
 
-                        let store = self.read_store(&mut pc);
-                        self.store(next_prop as u16, &store);
-                    },
-                    20 => { // add
-                        let lhs = self.get_value(&instr.ops[0]) as i16;
-                        let rhs = self.get_value(&instr.ops[1]) as i16;
-                        let store = self.read_store(&mut pc);
+    // each row starts under the first one, at the column it started in
+    fn print_table<W: Write>(&self, output: &mut W, rows: &[String]) {
+        let window = self.screen.borrow().window();
+        let (row, col) = self.screen.borrow().cursor();
+        for (i, text) in rows.iter().enumerate() {
+            match window {
+                ZWindow::Upper => self.screen.borrow_mut().set_cursor(row + i, col),
+                ZWindow::Lower if i > 0 => self.print(output, "\n"),
+                ZWindow::Lower => {},
+            }
+            self.print(output, text);
+        }
+    }
 
-                        let result = lhs.wrapping_add(rhs);
-                        self.store(result as u16, &store);
-                    },
-                    21 => { // sub
-                        let lhs = self.get_value(&instr.ops[0]) as i16;
-                        let rhs = self.get_value(&instr.ops[1]) as i16;
-                        let store = self.read_store(&mut pc);
+    fn print<W: Write>(&self, output: &mut W, text: &str) {
+        let mut streams = self.streams.borrow_mut();
+        if streams.capture(text) {
+            return;
+        }
 
-                        let result = lhs.wrapping_sub(rhs);
-                        self.store(result as u16, &store);
+        let mut screen = self.screen.borrow_mut();
+        let window = screen.window();
+        if streams.screen() {
+            match window {
+                ZWindow::Lower => write!(output, "{}", text).unwrap(),
+                ZWindow::Upper => screen.print_upper(text),
+            }
+        }
+
+        if window == ZWindow::Lower && self.transcripting() {
+            streams.transcribe(text);
+        }
+    }
+
+    fn version(&self) -> u8 {
+        self.memory.borrow().header().version()
+    }
+
+    fn status_line(&self) -> Result<StatusLine, ZMachineError> {
+        let mem = self.memory.borrow();
+
+        let location = match u16::from(mem.global(0)?) {
+            0 => String::new(),
+            obj => mem.get_object_name(obj)?,
+        };
+        let first: u16 = mem.global(1)?.into();
+        let second: u16 = mem.global(2)?.into();
+
+        let progress = if mem.header().time_game() {
+            StatusProgress::Time { hours: first, minutes: second }
+        } else {
+            StatusProgress::Score { score: first as i16, turns: second }
+        };
+
+        Ok(StatusLine { location, progress })
+    }
+
+    fn fetch_next_instr(&self) -> Result<(Instruction, usize), ExecError> {
+        let mut stack = self.stack.borrow_mut();
+        let mem = self.memory.borrow();
+
+        let idx = stack.len() - 1;
+        let frame = &mut stack[idx];
+
+        let start = frame.pc;
+        let code = mem.slice(start);
+        if code.is_empty() {
+            return Err(ExecError {
+                pc: start,
+                bytes: Vec::new(),
+                form: InstructionType::ZeroOps,
+                error: ZMachineError::OutOfBounds(start),
+            });
+        }
+
+        // decode from a zero padded copy so a truncated instruction at the
+        // very end of memory can't read past it
+        let mut buf = [0u8; 24];
+        let len = code.len().min(buf.len());
+        buf[..len].copy_from_slice(&code[..len]);
+
+        match Instruction::from_mem(&buf) {
+            Ok((instr, offset)) => {
+                frame.pc += offset;
+                Ok((instr, start))
+            },
+            Err(e) => Err(ExecError {
+                pc: start,
+                bytes: code.iter().take(1).cloned().collect(),
+                form: e.form,
+                error: e.error,
+            }),
+        }
+    }
+
+    fn exec_error(&self, instr: &Instruction, start: usize, end: usize, error: ZMachineError) -> ZMachineExecResult {
+        let mem = self.memory.borrow();
+
+        ZMachineExecResult::Error(ExecError {
+            pc: start,
+            bytes: mem.slice(start).iter().take(end - start).cloned().collect(),
+            form: instr.ty,
+            error,
+        })
+    }
+
+    fn request_input(&self, request: InputRequest, tenths: u16, routine: u16) -> ZMachineExecResult {
+        self.input.replace(Some(request));
+
+        let mut timer = self.timer.borrow_mut();
+        if self.profile.borrow().timed_input {
+            timer.start(tenths, routine);
+        } else {
+            timer.stop();
+        }
+        drop(timer);
+
+        self.input_request()
+    }
+
+    fn input_request(&self) -> ZMachineExecResult {
+        let tenths = self.timer.borrow().tenths();
+        match (self.input.borrow().as_ref(), tenths) {
+            (Some(InputRequest::Char(_)), Some(tenths)) => ZMachineExecResult::NeedTimedChar(tenths),
+            (Some(InputRequest::Char(_)), None) => ZMachineExecResult::NeedChar,
+            (_, Some(tenths)) => ZMachineExecResult::NeedTimedInput(tenths),
+            (_, None) => ZMachineExecResult::NeedInput,
+        }
+    }
+
+    fn get_pc(&self) -> usize {
+        let stack = self.stack.borrow();
+        let idx = stack.len() - 1;
+
+        stack[idx].pc
+    }
+
+    fn set_pc(&self, pc: usize) {
+        let mut stack = self.stack.borrow_mut();
+        let idx = stack.len() - 1;
+
+        stack[idx].pc = pc;
+    }
+
+    fn read_store(&self, pc: &mut usize) -> Result<Address, ZMachineError> {
+        let mem = self.memory.borrow();
+        let addr = Address::of(mem.read_byte(*pc)? as u16);
+        *pc += 1;
+
+        Ok(addr)
+    }
+
+    pub fn send_input(&self, input: &str) -> Result<(), ZMachineError> {
+        self.send_terminated_input(input, 13)
+    }
+
+    // For a line ended by one of the game's `terminating_keys` instead of
+    // return. Any other key counts as return.
+    pub fn send_terminated_input(&self, input: &str, key: u8) -> Result<(), ZMachineError> {
+        let request = self.input.borrow_mut().take();
+        if let Some(InputRequest::Line { text, parse, store }) = request {
+            self.timer.borrow_mut().stop();
+            self.finish_line(text, parse, input)?;
+            if let Some(store) = store {
+                let key = if self.terminating_keys().contains(&key) { key } else { 13 };
+                self.store(key as u16, &store)?;
+            }
+
+            let command = input.trim_end_matches(&['\r', '\n'][..]);
+            let mut streams = self.streams.borrow_mut();
+            if self.transcripting() {
+                streams.transcribe(command);
+                streams.transcribe("\n");
+            }
+            streams.record(command);
+            streams.flush();
+        } else {
+            self.input.replace(request);
+        }
+
+        Ok(())
+    }
+
+    // Newline, escape and delete come through as the matching ZSCII keys,
+    // anything else without a ZSCII code as '?'.
+    pub fn send_char(&self, ch: char) -> Result<(), ZMachineError> {
+        let request = self.input.borrow_mut().take();
+        if let Some(InputRequest::Char(store)) = request {
+            self.timer.borrow_mut().stop();
+            let zscii = match ch {
+                '\n' | '\r' => 13,
+                '\x08' | '\x7f' => 8,
+                '\x1b' => 27,
+                ' '..='~' => ch as u16,
+                _ => '?' as u16,
+            };
+            self.store(zscii, &store)?;
+
+            let key = if zscii == 13 { String::new() } else { ch.to_string() };
+            let mut streams = self.streams.borrow_mut();
+            streams.record(&key);
+            streams.flush();
+        } else {
+            self.input.replace(request);
+        }
+
+        Ok(())
+    }
+
+    // The host's wait for timed input ran out. The game's interrupt routine
+    // runs the next time the machine does.
+    pub fn send_timeout(&self) {
+        if self.input.borrow().is_some() {
+            self.timer.borrow_mut().time_out();
+        }
+    }
+
+    // V5+ games can leave text in the buffer for the player to edit. The
+    // line sent back replaces it.
+    pub fn preloaded_input(&self) -> String {
+        let mem = self.memory.borrow();
+        match self.input.borrow().as_ref() {
+            Some(InputRequest::Line { text, .. }) if mem.header().version() >= 5 => {
+                let (_, preloaded) = mem.read_text(*text).unwrap_or_default();
+                preloaded.into_iter().map(|c| c as char).collect()
+            },
+            _ => String::new(),
+        }
+    }
+
+    // V5+: the function keys (129-154) and mouse clicks (252-254) that the
+    // game's table at 0x2E lets end a line
+    pub fn terminating_keys(&self) -> Vec<u8> {
+        if self.story.is_empty() {
+            return Vec::new();
+        }
+
+        let mem = self.memory.borrow();
+        let header = mem.header();
+        if header.version() < 5 || header.terminating_chars() == 0 {
+            return Vec::new();
+        }
+
+        mem.slice(header.terminating_chars()).iter()
+            .take_while(|key| **key != 0)
+            .flat_map(|key| match key {
+                // every function key
+                255 => (129..=154).collect(),
+                129..=154 | 252..=254 => vec![*key],
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    // a parse buffer of 0 leaves the text for the game to tokenise
+    fn finish_line(&self, text: u16, parse: u16, input: &str) -> Result<(), ZMachineError> {
+        let mut mem = self.memory.borrow_mut();
+        mem.write_text(text, input)?;
+
+        if parse == 0 && mem.header().version() >= 5 {
+            return Ok(());
+        }
+        let analyzer = ZLexicalAnalyzer::new(text, parse);
+        analyzer.run(&mut mem)
+    }
+
+    // an interrupt routine returning true ends the input with nothing typed
+    fn end_input(&self) -> Result<(), ZMachineError> {
+        self.timer.borrow_mut().stop();
+        let request = self.input.borrow_mut().take();
+        match request {
+            Some(InputRequest::Line { text, parse, store }) => {
+                self.finish_line(text, parse, "")?;
+                match store {
+                    Some(store) => self.store(0, &store),
+                    None => Ok(()),
+                }
+            },
+            Some(InputRequest::Char(store)) => self.store(0, &store),
+            None => Ok(()),
+        }
+    }
+
+    pub fn send_save_result(&self, saved: bool) -> Result<(), ZMachineError> {
+        let request = self.save_request.borrow_mut().take();
+        if let Some(SaveRequest::Save) = request {
+            self.resume_branch(saved)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn send_restore(&self, data: Option<&[u8]>) -> Result<(), QuetzalError> {
+        if self.save_request.borrow_mut().take() != Some(SaveRequest::Restore) {
+            return Ok(());
+        }
+
+        let result = data.map(|data| self.restore_state(data));
+
+        // a successful restore lands on the branch of the original save instruction
+        self.resume_branch(matches!(result, Some(Ok(()))))
+            .map_err(|_| QuetzalError::Malformed("PC does not follow a save instruction"))?;
+
+        result.unwrap_or(Ok(()))
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mem = self.memory.borrow();
+        let stack = self.stack.borrow();
+        let header = mem.header();
+
+        let mut frames: Vec<QuetzalFrame> = Vec::new();
+        for (i, frame) in stack.iter().enumerate() {
+            let return_pc = if i == 0 { 0 } else { stack[i - 1].pc };
+            frames.push(QuetzalFrame {
+                return_pc,
+                discard: i > 0 && frame.ret_addr.is_none(),
+                store: frame.ret_addr.as_ref().map(|addr| addr.addr() as u8).unwrap_or(0),
+                // one bit per argument supplied
+                args: ((1u16 << frame.args.min(7)) - 1) as u8,
+                locals: frame.locals.clone(),
+                stack: frame.stack.clone(),
+            });
+        }
+
+        let save = QuetzalSave {
+            release: header.release(),
+            serial: header.serial(),
+            checksum: header.checksum(),
+            pc: stack[stack.len() - 1].pc,
+            memory: mem.dynamic().to_vec(),
+            frames,
+        };
+
+        save.to_bytes(&self.story[..mem.static_base()])
+    }
+
+    fn restore_state(&self, data: &[u8]) -> Result<(), QuetzalError> {
+        let mut mem = self.memory.borrow_mut();
+        let save = QuetzalSave::from_bytes(data, &self.story[..mem.static_base()])?;
+
+        let header = mem.header();
+        if save.release != header.release() || save.serial != header.serial() || save.checksum != header.checksum() {
+            return Err(QuetzalError::WrongStory);
+        }
+
+        let mut stack: Vec<StackFrame> = Vec::new();
+        for (i, frame) in save.frames.iter().enumerate() {
+            let pc = save.frames.get(i + 1)
+                .map(|next| next.return_pc)
+                .unwrap_or(save.pc);
+
+            stack.push(StackFrame {
+                locals: frame.locals.clone(),
+                stack: frame.stack.clone(),
+                ret_addr: if frame.discard { None } else { Some(Address::of(frame.store as u16)) },
+                pc,
+                args: frame.args.trailing_ones() as usize,
+                interrupt: false,
+            });
+        }
+
+        mem.restore_dynamic(&save.memory);
+        drop(mem);
+        self.stack.replace(stack);
+        self.undo.borrow_mut().clear();
+        self.write_profile();
+
+        Ok(())
+    }
+
+    fn push_undo(&self, stack: Vec<StackFrame>, point: UndoPoint) {
+        let mem = self.memory.borrow();
+        self.undo.borrow_mut().push(mem.dynamic(), (stack, point));
+    }
+
+    // the last save_undo, skipping any host checkpoints on the way
+    fn restore_undo(&self) -> Result<bool, ZMachineError> {
+        while let Some(((stack, point), memory)) = self.undo.borrow_mut().pop() {
+            if let UndoPoint::SaveUndo = point {
+                self.memory.borrow_mut().restore_dynamic(&memory);
+                self.stack.replace(stack);
+                self.resume_store(2)?;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn resume_store(&self, val: u16) -> Result<(), ZMachineError> {
+        let mut pc = self.get_pc();
+        let store = self.read_store(&mut pc)?;
+        self.store(val, &store)?;
+
+        self.set_pc(pc);
+        Ok(())
+    }
+
+    fn resume_branch(&self, cond: bool) -> Result<(), ZMachineError> {
+        let mut pc = self.get_pc();
+        let offset = self.read_offset(&mut pc)?;
+        if cond == offset.target {
+            self.branch(offset, &mut pc)?;
+        }
+
+        self.set_pc(pc);
+        Ok(())
+    }
+
+    // errors that happen between instructions, while finishing off one the
+    // host was asked to help with
+    fn resume_error(&self, form: InstructionType, error: ZMachineError) -> ZMachineExecResult {
+        ZMachineExecResult::Error(ExecError {
+            pc: self.get_pc(),
+            bytes: Vec::new(),
+            form,
+            error,
+        })
+    }
+
+    pub fn exec<W: Write>(&self, output: &mut W) -> ZMachineExecResult {
+        if self.story.is_empty() {
+            return ZMachineExecResult::Error(ExecError {
+                pc: 0,
+                bytes: Vec::new(),
+                form: InstructionType::ZeroOps,
+                error: ZMachineError::NoStory,
+            });
+        }
+
+        // a host that never answered a save or restore gets a failed one
+        if self.save_request.borrow_mut().take().is_some() {
+            if let Err(e) = self.resume_branch(false) {
+                return self.resume_error(InstructionType::ZeroOps, e);
+            }
+        }
+
+        if self.input.borrow().is_some() {
+            match self.resume_input(output) {
+                Ok(None) => {},
+                Ok(Some(request)) => return request,
+                Err(e) => return self.resume_error(InstructionType::Variable, e),
+            }
+        }
+
+        loop {
+            let finished = self.sound.borrow_mut().take_finished();
+            if let Some(routine) = finished {
+                // the result goes nowhere
+                let routine_addr = self.memory.borrow().header().routine_address(routine);
+                if let Err(e) = self.call(routine_addr, &[], None, self.get_pc()) {
+                    return self.resume_error(InstructionType::Variable, e);
+                }
+            }
+
+            let result = self.exec_one(output);
+            match result {
+                // only an interrupt routine runs while input is waiting
+                ZMachineExecResult::Next if self.input.borrow().is_none() => continue,
+                ZMachineExecResult::Next |
+                ZMachineExecResult::NeedInput |
+                ZMachineExecResult::NeedChar |
+                ZMachineExecResult::NeedTimedInput(_) |
+                ZMachineExecResult::NeedTimedChar(_) => match self.resume_input(output) {
+                    Ok(None) => continue,
+                    Ok(Some(request)) => return request,
+                    Err(e) => return self.resume_error(InstructionType::Variable, e),
+                },
+                result => return result
+            }
+        }
+    }
+
+    // Works out what to do while the game waits for input: carry on with an
+    // interrupt routine, act on one that just returned or start one for a
+    // timeout, take replayed input, or ask the host.
+    fn resume_input<W: Write>(&self, output: &mut W) -> Result<Option<ZMachineExecResult>, ZMachineError> {
+        let mut timer = self.timer.borrow_mut();
+        if timer.running() {
+            return Ok(None);
+        }
+
+        match timer.take_result() {
+            Some(0) => timer.restart(),
+            Some(_) => {
+                drop(timer);
+                self.end_input()?;
+                return Ok(None);
+            },
+            None => {},
+        }
+
+        if let Some(routine) = timer.take_timeout() {
+            timer.interrupt();
+            drop(timer);
+
+            let routine_addr = self.memory.borrow().header().routine_address(routine);
+            self.call(routine_addr, &[], None, self.get_pc())?;
+            let mut stack = self.stack.borrow_mut();
+            let idx = stack.len() - 1;
+            stack[idx].interrupt = true;
+            return Ok(None);
+        }
+        drop(timer);
+
+        if self.replay_input(output)? {
+            Ok(None)
+        } else {
+            Ok(Some(self.input_request()))
+        }
+    }
+
+    fn replay_input<W: Write>(&self, output: &mut W) -> Result<bool, ZMachineError> {
+        let command = self.streams.borrow_mut().next_command();
+        let command = match command {
+            Some(command) => command,
+            None => return Ok(false),
+        };
+
+        let want_char = matches!(*self.input.borrow(), Some(InputRequest::Char(_)));
+        if want_char {
+            // one key per line, an empty line is return
+            let ch = command.trim_end_matches(&['\r', '\n'][..]).chars().next().unwrap_or('\n');
+            self.send_char(ch)?;
+        } else {
+            writeln!(output, "{}", command.trim_end()).unwrap();
+            self.send_input(&command)?;
+        }
+        Ok(true)
+    }
+
+    fn exec_one<W: Write>(&self, output: &mut W) -> ZMachineExecResult {
+        let (instr, start) = match self.fetch_next_instr() {
+            Ok(fetched) => fetched,
+            Err(e) => return ZMachineExecResult::Error(e),
+        };
+        let end = self.get_pc();
+
+        match self.exec_instr(&instr, output) {
+            Ok(result) => result,
+            Err(e) => self.exec_error(&instr, start, end, e),
+        }
+    }
+
+    fn exec_instr<W: Write>(&self, instr: &Instruction, output: &mut W) -> Result<ZMachineExecResult, ZMachineError> {
+        let mut pc = self.get_pc();
+
+        match instr.ty {
+            InstructionType::Long => {
+                match instr.opcode {
+                    1 => { // jump equal
+                        let test = self.operand(instr, 0)? as i16;
+                        let offset = self.read_offset(&mut pc)?;
+                        let matches = self.operands(&instr.ops[1..])?.iter().any(|val| *val as i16 == test);
+                        if matches == *offset.target() {
+                            self.branch(offset, &mut pc)?;
+                        }
+                    }
+                    2 => { // jump less than
+                        let lhs = self.operand(instr, 0)? as i16;
+                        let rhs = self.operand(instr, 1)? as i16;
+                        let cond = lhs < rhs;
+
+                        let offset = self.read_offset(&mut pc)?;
+                        if cond == *offset.target() {
+                            self.branch(offset, &mut pc)?;
+                        }
+                    },
+                    3 => { // jump greater than
+                        let lhs = self.operand(instr, 0)? as i16;
+                        let rhs = self.operand(instr, 1)? as i16;
+                        let cond = lhs > rhs;
+
+                        let offset = self.read_offset(&mut pc)?;
+                        if cond == *offset.target() {
+                            self.branch(offset, &mut pc)?;
+                        }
+                    },
+                    4 => { // dec check
+                        let var_num = self.operand(instr, 0)?;
+                        let val = self.operand(instr, 1)? as i16;
+
+                        let var = (self.load_indirect(var_num)? as i16).wrapping_sub(1);
+                        let cond = var < val;
+
+                        self.store_indirect(var_num, var as u16)?;
+
+                        let offset = self.read_offset(&mut pc)?;
+                        if cond == offset.target {
+                            self.branch(offset, &mut pc)?;
+                        }
+                    },
+                    5 => { // inc check
+                        let var_num = self.operand(instr, 0)?;
+                        let val = self.operand(instr, 1)? as i16;
+
+                        let var = (self.load_indirect(var_num)? as i16).wrapping_add(1);
+                        let cond = var > val;
+
+                        self.store_indirect(var_num, var as u16)?;
+
+                        let offset = self.read_offset(&mut pc)?;
+                        if cond == offset.target {
+                            self.branch(offset, &mut pc)?;
+                        }
+                    },
+                    6 => { //jump in
+                        let test_obj = self.operand(instr, 0)?;
+                        let test_parent = self.operand(instr, 1)?;
+                        let parent = {
+                            let mem = self.memory.borrow();
+                            mem.get_object_parent(test_obj)?
+                        };
+                        let cond = parent == test_parent;
+
+                        let offset = self.read_offset(&mut pc)?;
+                        if cond == offset.target {
+                            self.branch(offset, &mut pc)?;
+                        }
+                    }
+                    7 => { // test
+                        let bm = self.operand(instr, 0)?;
+                        let flags = self.operand(instr, 1)?;
+                        let cond = bm & flags == flags;
+
+                        let offset = self.read_offset(&mut pc)?;
+                        if cond == offset.target {
+                            self.branch(offset, &mut pc)?;
+                        }
+                    },
+                    8 => { // or
+                        let store = self.read_store(&mut pc)?;
+                        let lhs = self.operand(instr, 0)?;
+                        let rhs = self.operand(instr, 1)?;
+
+                        let result = lhs | rhs;
+
+                        self.store(result, &store)?;
+                    },
+                    9 => { // AND
+                        let store = self.read_store(&mut pc)?;
+                        let lhs = self.operand(instr, 0)?;
+                        let rhs = self.operand(instr, 1)?;
+
+                        let result = lhs & rhs;
+
+                        self.store(result, &store)?;
+                    },
+                    10 => { // test_attr
+                        let obj_num = self.operand(instr, 0)?;
+                        let attr = self.operand(instr, 1)?;
+                        let offset = self.read_offset(&mut pc)?;
+
+                        let attr = {
+                            let mem = self.memory.borrow();
+                            mem.test_attr(obj_num, attr)?
+                        };
+
+                        if attr == offset.target {
+                            self.branch(offset, &mut pc)?;
+                        }
+                    },
+                    11 => { // set attr
+                        let obj_num = self.operand(instr, 0)?;
+                        let attr = self.operand(instr, 1)?;
+                        let mut mem = self.memory.borrow_mut();
+                        mem.set_attr(obj_num, attr)?;
+                    },
+                    12 => { // clear attr
+                        let obj_num = self.operand(instr, 0)?;
+                        let attr = self.operand(instr, 1)?;
+                        let mut mem = self.memory.borrow_mut();
+                        mem.clear_attr(obj_num, attr)?;
+                    },
+                    13 => { // store
+                        let var_num = self.operand(instr, 0)?;
+                        let val = self.operand(instr, 1)?;
+
+                        self.store_indirect(var_num, val)?;
+                    },
+                    14 => { // insert_obj
+                        let obj_num = self.operand(instr, 0)?;
+                        let new_parent = self.operand(instr, 1)?;
+
+                        let mut mem = self.memory.borrow_mut();
+                        mem.insert_object(obj_num, new_parent)?;
+                    }
+                    15 => { // loadw
+                        let store = self.read_store(&mut pc)?;
+
+                        let addr = self.operand(instr, 0)?;
+                        let idx = self.operand(instr, 1)?;
+                        let addr = Address::Word(addr.wrapping_add(idx.wrapping_mul(2)) as usize);
+
+                        let val = self.get_value(&Operand::Variable(addr))?;
+                        self.store(val, &store)?;
+                    },
+                    16 => { // loadb
+                        let addr = self.operand(instr, 0)?;
+                        let idx = self.operand(instr, 1)?;
+                        let store = self.read_store(&mut pc)?;
+                        let addr = Address::Byte(addr.wrapping_add(idx) as usize);
+
+                        let val = self.get_value(&Operand::Variable(addr))?;
+                        self.store(val, &store)?;
+                    },
+                    17 => { //get prop
+                        let obj = self.operand(instr, 0)?;
+                        let property = self.operand(instr, 1)?;
+                        let prop = {
+                            let mem = self.memory.borrow();
+                            mem.get_prop(obj, property)?
+                        };
+
+                        let p: u16 = prop.into();
+                        let store = self.read_store(&mut pc)?;
+                        self.store(p, &store)?;
+                    },
+                    18 => { //get prop addr
+                        let obj = self.operand(instr, 0)?;
+                        let prop_num = self.operand(instr, 1)?;
+                        let addr = {
+                            let mem = self.memory.borrow();
+                            mem.get_prop_addr(obj, prop_num)?
+                        };
+
+                        let store = self.read_store(&mut pc)?;
+                        self.store(addr, &store)?;
+                    },
+                    19 => { // get next prop
+                        let obj = self.operand(instr, 0)?;
+                        let prop_num = self.operand(instr, 1)?;
+                        let next_prop = {
+                            let mem = self.memory.borrow();
+                            mem.get_next_prop(obj, prop_num)?
+                        };
+
+                        let store = self.read_store(&mut pc)?;
+                        self.store(next_prop as u16, &store)?;
+                    },
+                    20 => { // add
+                        let lhs = self.operand(instr, 0)? as i16;
+                        let rhs = self.operand(instr, 1)? as i16;
+                        let store = self.read_store(&mut pc)?;
+
+                        let result = lhs.wrapping_add(rhs);
+                        self.store(result as u16, &store)?;
+                    },
+                    21 => { // sub
+                        let lhs = self.operand(instr, 0)? as i16;
+                        let rhs = self.operand(instr, 1)? as i16;
+                        let store = self.read_store(&mut pc)?;
+
+                        let result = lhs.wrapping_sub(rhs);
+                        self.store(result as u16, &store)?;
                     },
                     22 => { // mul
-                        let lhs = self.get_value(&instr.ops[0]) as i16;
-                        let rhs = self.get_value(&instr.ops[1]) as i16;
-                        let store = self.read_store(&mut pc);
+                        let lhs = self.operand(instr, 0)? as i16;
+                        let rhs = self.operand(instr, 1)? as i16;
+                        let store = self.read_store(&mut pc)?;
 
                         let result = lhs.wrapping_mul(rhs);
-                        self.store(result as u16, &store);
+                        self.store(result as u16, &store)?;
                     },
                     23 => { // div
-                        let lhs = self.get_value(&instr.ops[0]) as i16;
-                        let rhs = self.get_value(&instr.ops[1]) as i16;
-                        let store = self.read_store(&mut pc);
+                        let lhs = self.operand(instr, 0)? as i16;
+                        let rhs = self.operand(instr, 1)? as i16;
+                        let store = self.read_store(&mut pc)?;
 
-                        let result = lhs / rhs;
-                        self.store(result as u16, &store);
+                        if rhs == 0 {
+                            return Err(ZMachineError::DivideByZero);
+                        }
+                        let result = lhs.wrapping_div(rhs);
+                        self.store(result as u16, &store)?;
                     },
                     24 => { // mod a b
-                        let lhs = self.get_value(&instr.ops[0]) as i16;
-                        let rhs = self.get_value(&instr.ops[1]) as i16;
-                        let store = self.read_store(&mut pc);
+                        let lhs = self.operand(instr, 0)? as i16;
+                        let rhs = self.operand(instr, 1)? as i16;
+                        let store = self.read_store(&mut pc)?;
 
-                        let result = lhs % rhs;
-                        self.store(result as u16, &store);
+                        if rhs == 0 {
+                            return Err(ZMachineError::DivideByZero);
+                        }
+                        let result = lhs.wrapping_rem(rhs);
+                        self.store(result as u16, &store)?;
+                    },
+                    25 => { // call_2s
+                        let store = self.read_store(&mut pc)?;
+                        if self.call_routine(instr, Some(store), pc)? {
+                            return Ok(ZMachineExecResult::Next);
+                        }
+                    },
+                    26 => { // call_2n
+                        if self.call_routine(instr, None, pc)? {
+                            return Ok(ZMachineExecResult::Next);
+                        }
                     },
                     _code => {
-                        println!("Unimplemented long: {:?} pc {:x}", instr, pc);
-                        return ZMachineExecResult::Exit;
+                        return Err(ZMachineError::UnknownOpcode(instr.opcode));
                     }
                 }
 
@@ -610,321 +1484,510 @@ impl ZMachine {
             InstructionType::ZeroOps => {
                 match instr.opcode {
                     0 => { // return true
-                        pc = self.return_val(1);
+                        pc = self.return_val(1)?;
                     },
                     1 => { // return false
-                        pc = self.return_val(0);
+                        pc = self.return_val(0)?;
                     },
                     2 => { // PRINT!!!!
                         let mem = self.memory.borrow();
-                        let (message, offset) = mem.read_string(pc);
+                        let (message, offset) = mem.read_string(pc)?;
                         pc += offset;
 
-                        write!(output, "{}", message).unwrap();
+                        self.print(output, &message);
                     },
                     3 => { // print ret (true)
                         let (message, _) = {
                             let mem = self.memory.borrow();
-                            mem.read_string(pc)
+                            mem.read_string(pc)?
                         };
 
-                        write!(output, "{}", message).unwrap();
+                        self.print(output, &message);
+
+                        pc = self.return_val(1)?;
+                    },
+                    // V4 stores the result instead and V5 moves these to extended opcodes
+                    5 if self.version() <= 3 => { // save
+                        self.set_pc(pc);
+                        self.save_request.replace(Some(SaveRequest::Save));
 
-                        pc = self.return_val(1);
+                        return Ok(ZMachineExecResult::Save(self.save_state()));
+                    },
+                    6 if self.version() <= 3 => { // restore
+                        self.set_pc(pc);
+                        self.save_request.replace(Some(SaveRequest::Restore));
+
+                        return Ok(ZMachineExecResult::Restore);
+                    },
+                    7 => { // restart
+                        self.restart();
+
+                        return Ok(ZMachineExecResult::Next);
                     },
                     8 => { // ret popped
-                        let val = self.get_value(&Operand::Variable(Address::StackPointer));
-                        pc = self.return_val(val);
+                        let val = self.get_value(&Operand::Variable(Address::StackPointer))?;
+                        pc = self.return_val(val)?;
                     },
                     9 => { // pop
-                        let _ = self.get_value(&Operand::Variable(Address::StackPointer));
+                        let _ = self.get_value(&Operand::Variable(Address::StackPointer))?;
                     },
                     10 => { // quit
-                        return ZMachineExecResult::Exit;
+                        return Ok(ZMachineExecResult::Exit);
                     },
                     11 => { // newline
-                        write!(output, "{}", '\n').unwrap();
+                        self.print(output, "\n");
+                    },
+                    12 => { // show_status
+                        if self.version() <= 3 {
+                            self.set_pc(pc);
+                            return Ok(ZMachineExecResult::ShowStatus(self.status_line()?));
+                        }
+                    },
+                    13 => { // verify
+                        let cond = self.checksum() == self.header_checksum();
+
+                        let offset = self.read_offset(&mut pc)?;
+                        if cond == offset.target {
+                            self.branch(offset, &mut pc)?;
+                        }
                     },
                     _code => {
-                        println!("unimplemented no-op: {:?} pc: {:x}", instr, pc);
-                        return ZMachineExecResult::Exit;
+                        return Err(ZMachineError::UnknownOpcode(instr.opcode));
                     }
                 }
             },
             InstructionType::Short => {
                 match instr.opcode {
                     0 => { // jz
-                        let val = self.get_value(&instr.ops[0]) as i16;
-                        let offset = self.read_offset(&mut pc);
+                        let val = self.operand(instr, 0)? as i16;
+                        let offset = self.read_offset(&mut pc)?;
                         let cond = val == 0;
 
                         if cond == offset.target {
-                            self.branch(offset, &mut pc);
+                            self.branch(offset, &mut pc)?;
                         }
                     },
                     1 => { // get sibling
-                        let store = self.read_store(&mut pc);
-                        let offset = self.read_offset(&mut pc);
-                        let obj_num = self.get_value(&instr.ops[0]) as u8;
+                        let store = self.read_store(&mut pc)?;
+                        let offset = self.read_offset(&mut pc)?;
+                        let obj_num = self.operand(instr, 0)?;
 
                         let num = {
                             let mem = self.memory.borrow();
-                            mem.get_object_sibling(obj_num)
+                            mem.get_object_sibling(obj_num)?
                         };
 
                         if let Some(sib) = num {
-                            self.store(sib as u16, &store);
+                            self.store(sib, &store)?;
                             if *offset.target() {
-                                self.branch(offset, &mut pc);
+                                self.branch(offset, &mut pc)?;
                             }
                         } else {
-                            self.store(0, &store); // think i still need to do this?
+                            self.store(0, &store)?; // think i still need to do this?
                             if !offset.target() {
-                                self.branch(offset, &mut pc);
+                                self.branch(offset, &mut pc)?;
                             }
                         }
                     },
                     2 => { // get child
-                        let obj_num = self.get_value(&instr.ops[0]) as u8;
-                        let store = self.read_store(&mut pc);
-                        let offset = self.read_offset(&mut pc);
+                        let obj_num = self.operand(instr, 0)?;
+                        let store = self.read_store(&mut pc)?;
+                        let offset = self.read_offset(&mut pc)?;
 
                         let num = {
                             let mem = self.memory.borrow();
-                            mem.get_object_child(obj_num)
+                            mem.get_object_child(obj_num)?
                         };
 
                         if let Some(child) = num {
-                            self.store(child as u16, &store);
+                            self.store(child, &store)?;
                             if *offset.target() {
-                                self.branch(offset, &mut pc);
+                                self.branch(offset, &mut pc)?;
                             }
                         } else {
-                            self.store(0, &store); // think i still need to do this?
+                            self.store(0, &store)?; // think i still need to do this?
                             if !*offset.target() {
-                                self.branch(offset, &mut pc);
+                                self.branch(offset, &mut pc)?;
                             }
                         }
                     }
                     3 => { // get parent
-                        let store = self.read_store(&mut pc);
-                        let obj_num = self.get_value(&instr.ops[0]) as u8;
+                        let store = self.read_store(&mut pc)?;
+                        let obj_num = self.operand(instr, 0)?;
 
                         let num = {
                             let mem = self.memory.borrow();
-                            mem.get_object_parent(obj_num)
+                            mem.get_object_parent(obj_num)?
                         };
 
-                        self.store(num as u16, &store);
+                        self.store(num, &store)?;
                     },
                     4 => { // get prop len
-                        let store = self.read_store(&mut pc);
-                        let prop_addr = self.get_value(&instr.ops[0]);
+                        let store = self.read_store(&mut pc)?;
+                        let prop_addr = self.operand(instr, 0)?;
 
                         let prop_len = {
                             let mem = self. memory.borrow();
-                            mem.get_prop_len(prop_addr)
+                            mem.get_prop_len(prop_addr)?
                         };
 
-                        self.store(prop_len, &store);
+                        self.store(prop_len, &store)?;
                     },
                     5 => { //inc
-                        let var_num = self.get_value(&instr.ops[0]);
-                        let var_addr = Address::of(var_num);
-                        let op = Operand::Variable(var_addr);
+                        let var_num = self.operand(instr, 0)?;
 
-                        let var = self.get_value(&op) as i16;
-                        let var = var.wrapping_add(1);
-
-                        let addr = Address::of(var_num);
-                        self.store(var as u16, &addr);
+                        let var = (self.load_indirect(var_num)? as i16).wrapping_add(1);
+                        self.store_indirect(var_num, var as u16)?;
                     },
                     6 => { //dec
-                        let var_num = self.get_value(&instr.ops[0]);
-                        let var_addr = Address::of(var_num);
-                        let op = Operand::Variable(var_addr);
-
-                        let var = self.get_value(&op) as i16;
-                        let var = var.wrapping_sub(1);
+                        let var_num = self.operand(instr, 0)?;
 
-                        let addr = Address::of(var_num);
-                        self.store(var as u16, &addr);
+                        let var = (self.load_indirect(var_num)? as i16).wrapping_sub(1);
+                        self.store_indirect(var_num, var as u16)?;
                     },
                     7 => { // print addr
 
-                        let addr = self.get_value(&instr.ops[0]);
+                        let addr = self.operand(instr, 0)?;
                         let mem = self.memory.borrow();
-                        let (message, _) = mem.read_string(addr as usize);
+                        let (message, _) = mem.read_string(addr as usize)?;
 
-                        write!(output, "{}", message).unwrap();
+                        self.print(output, &message);
+                    },
+                    8 => { // call_1s
+                        let store = self.read_store(&mut pc)?;
+                        if self.call_routine(instr, Some(store), pc)? {
+                            return Ok(ZMachineExecResult::Next);
+                        }
                     },
                     9 => { // remove obj
-                        let obj_num = self.get_value(&instr.ops[0]);
+                        let obj_num = self.operand(instr, 0)?;
                         let mut mem = self.memory.borrow_mut();
-                        mem.remove_obj(obj_num as u8);
+                        mem.remove_obj(obj_num)?;
                     },
                     10 => { // print obj
-                        let obj_num = self.get_value(&instr.ops[0]);
+                        let obj_num = self.operand(instr, 0)?;
                         let mem = self.memory.borrow();
-                        let name = mem.get_object_name(obj_num as u8).expect("Tried to get name of invalid object");
-                        write!(output, "{}", name).unwrap();
+                        let name = mem.get_object_name(obj_num)?;
+                        self.print(output, &name);
                     },
                     11 => { // return value
-                        let val = self.get_value(&instr.ops[0]);
-                        pc = self.return_val(val);
+                        let val = self.operand(instr, 0)?;
+                        pc = self.return_val(val)?;
                     },
                     12 => { // jump
-                        let jmp = self.get_value(&instr.ops[0]) as i16;
+                        let jmp = self.operand(instr, 0)? as i16;
                         let offset = BranchOffset::new(true, Offset::Signed(jmp));
-                        self.branch(offset, &mut pc);
+                        self.branch(offset, &mut pc)?;
                     },
                     13 => { // print paddr
-                        let addr = self.get_value(&instr.ops[0]);
+                        let addr = self.operand(instr, 0)?;
                         let mem = self.memory.borrow();
-                        let (message, _) = mem.read_string(addr as usize * 2);
+                        let (message, _) = mem.read_string(mem.header().string_address(addr))?;
 
-                        write!(output, "{}", message).unwrap();
+                        self.print(output, &message);
                     },
-                    14 => {
-                        let addr_raw = self.get_value(&instr.ops[0]);
-                        let addr = Address::of(addr_raw);
-                        let op = Operand::Variable(Address::of(addr_raw));
-                        let val = self.get_value(&op);
-                        // in place - TODO: not like this >:(
-                        self.store(val, &addr);
+                    14 => { // load
+                        let var_num = self.operand(instr, 0)?;
+                        let val = self.load_indirect(var_num)?;
 
-                        let store = self.read_store(&mut pc);
-                        self.store(val, &store);
+                        let store = self.read_store(&mut pc)?;
+                        self.store(val, &store)?;
+                    },
+                    15 if self.version() >= 5 => { // call_1n
+                        if self.call_routine(instr, None, pc)? {
+                            return Ok(ZMachineExecResult::Next);
+                        }
                     },
                     15 => {//not
-                        let val = self.get_value(&instr.ops[0]);
-                        let store = self.read_store(&mut pc);
+                        let val = self.operand(instr, 0)?;
+                        let store = self.read_store(&mut pc)?;
                         let result = !val;
-                        self.store(result, &store);
+                        self.store(result, &store)?;
                     },
                     _ => {
-                        println!("Unimplemented short: {:?} pc {:x}", instr, pc);
-                        return ZMachineExecResult::Exit;
+                        return Err(ZMachineError::UnknownOpcode(instr.opcode));
                     }
                 }
             },
             InstructionType::Variable => {
                 match instr.opcode {
-                    0 => { // call
-                        let store = self.read_store(&mut pc);
-
-                        let routine_addr = self.get_value(&instr.ops[0]) as usize * 2;
-                        if routine_addr == 0 {
-                            // nothing happens and the return value is 0
-                            self.store(0, &store);
-                        } else {
-                            let mem = self.memory.borrow();
-                            let n_locals = mem.read_byte(routine_addr) as usize;
-
-                            let mut locals: Vec<u16> = Vec::new();
-                            for i in 0..n_locals {
-                                locals.push(mem.read_word(routine_addr + 1 + i * 2).into());
-                            }
-
-                            for (n, op) in instr.ops[1..].iter().enumerate() {
-                                if locals.len() > n {
-                                    let val = self.get_value(op);
-                                    locals[n] = val;
-                                }
-                            }
-
-                            let mut stack = self.stack.borrow_mut();
-                            let idx = stack.len() - 1;
-                            let current_frame = &mut stack[idx];
-                            current_frame.pc = pc;
-
-                            stack.push(StackFrame {
-                                locals,
-                                stack: Vec::new(),
-                                pc: routine_addr as usize + 1 + n_locals as usize * 2,
-                                ret_addr: store,
-                            });
-
-                            // return true here, we've already updated the pc
-                            return ZMachineExecResult::Next;
+                    0 | 12 => { // call (call_vs), call_vs2
+                        let store = self.read_store(&mut pc)?;
+                        if self.call_routine(instr, Some(store), pc)? {
+                            // return here, we've already updated the pc
+                            return Ok(ZMachineExecResult::Next);
                         }
                     },
                     1 => { // storew
-                        let addr = self.get_value(&instr.ops[0]);
-                        let idx = self.get_value(&instr.ops[1]);
-                        let val = self.get_value(&instr.ops[2]);
-                        let addr = Address::Word(addr + (2 * idx));
+                        let addr = self.operand(instr, 0)?;
+                        let idx = self.operand(instr, 1)?;
+                        let val = self.operand(instr, 2)?;
+                        let addr = Address::Word(addr.wrapping_add(idx.wrapping_mul(2)) as usize);
 
-                        self.store(val, &addr);
+                        self.store(val, &addr)?;
                     },
                     2 => { // storeb
-                        let addr = self.get_value(&instr.ops[0]);
-                        let idx = self.get_value(&instr.ops[1]);
-                        let val = self.get_value(&instr.ops[2]);
-                        let addr = Address::Byte(addr + idx);
+                        let addr = self.operand(instr, 0)?;
+                        let idx = self.operand(instr, 1)?;
+                        let val = self.operand(instr, 2)?;
+                        let addr = Address::Byte(addr.wrapping_add(idx) as usize);
 
-                        self.store(val, &addr);
+                        self.store(val, &addr)?;
                     }
                     3 => { // put prop
-                        let obj_num = self.get_value(&instr.ops[0]);
-                        let prop_num = self.get_value(&instr.ops[1]) as u8;
-                        let val = self.get_value(&instr.ops[2]);
+                        let obj_num = self.operand(instr, 0)?;
+                        let prop_num = self.operand(instr, 1)?;
+                        let val = self.operand(instr, 2)?;
 
                         let mut mem = self.memory.borrow_mut();
-                        mem.put_prop(obj_num as u8, prop_num, val.into());
+                        mem.put_prop(obj_num, prop_num, val.into())?;
                     },
                     4 => { // read!
-                        let text_buffer_addr = self.get_value(&instr.ops[0]);
-                        let parse_buffer_addr = self.get_value(&instr.ops[1]);
-
-                        self.store_text_buffer(text_buffer_addr, parse_buffer_addr);
+                        let text = self.operand(instr, 0)?;
+                        let parse = self.operand(instr, 1)?;
+                        let time = self.operand_or(instr, 2, 0)?;
+                        let routine = self.operand_or(instr, 3, 0)?;
+
+                        let store = if self.version() >= 5 {
+                            let store = self.read_store(&mut pc)?;
+                            self.set_pc(pc);
+                            Some(store)
+                        } else {
+                            None
+                        };
 
-                        return ZMachineExecResult::NeedInput;
+                        let request = self.request_input(InputRequest::Line { text, parse, store }, time, routine);
+                        if self.version() <= 3 {
+                            return Ok(ZMachineExecResult::ShowStatus(self.status_line()?));
+                        }
+                        return Ok(request);
                     },
                     5 => { // print char
-                        let ch = self.get_value(&instr.ops[0]) as u8;
-                        write!(output, "{}", ch as char).unwrap();
+                        let ch = self.operand(instr, 0)? as u8;
+                        self.print(output, &(ch as char).to_string());
                     },
                     6 => { // print num
-                        let val = self.get_value(&instr.ops[0]) as i16;
-                        write!(output, "{}", val as i16).unwrap();
+                        let val = self.operand(instr, 0)? as i16;
+                        self.print(output, &val.to_string());
                     },
                     7 => { //random
-                        let val = self.get_value(&instr.ops[0]) as i16;
+                        let val = self.operand(instr, 0)? as i16;
 
                         let mut rng = self.rng.borrow_mut();
-                        let result = if val == 0 {
-                            rng.seed();
-                            0
-                        } else if val < 0 {
-                            rng.seed_with(val as u64);
+                        let result = if val <= 0 {
+                            rng.reseed(val.unsigned_abs());
                             0
                         } else {
-                            rng.range(1..val as u16)
+                            rng.next(val as u16)
                         };
 
-                        let store = self.read_store(&mut pc);
-                        self.store(result, &store);
+                        let store = self.read_store(&mut pc)?;
+                        self.store(result, &store)?;
                     },
                     8 => { //push
-                        let val = self.get_value(&instr.ops[0]);
+                        let val = self.operand(instr, 0)?;
                         let mut stack = self.stack.borrow_mut();
                         let idx = stack.len() - 1;
                         let current_frame = &mut stack[idx];
                         current_frame.stack.push(val);
                     },
-                    9 => { //pull
-                        let var_num = self.get_value(&instr.ops[0]);
-                        let addr = Address::of(var_num);
-                        let op = Operand::Variable(Address::of(var_num));
-                        let val = self.get_value(&Operand::Variable(Address::StackPointer));
-
-                        let _ = self.get_value(&op);
-                        self.store(val, &addr);
+                    9 => { //pull
+                        let var_num = self.operand(instr, 0)?;
+                        let val = self.get_value(&Operand::Variable(Address::StackPointer))?;
+
+                        self.store_indirect(var_num, val)?;
+                    },
+                    10 => { // split_window
+                        let lines = self.operand(instr, 0)?;
+                        let version = self.version();
+                        self.screen.borrow_mut().split(lines as usize, version);
+                    },
+                    11 => { // set_window
+                        let window = match self.operand(instr, 0)? {
+                            0 => ZWindow::Lower,
+                            _ => ZWindow::Upper,
+                        };
+                        self.screen.borrow_mut().select(window);
+                    },
+                    19 => { // output_stream
+                        let stream = self.operand(instr, 0)? as i16;
+                        let table = if stream == 3 { Some(self.operand(instr, 1)?) } else { None };
+                        self.select_stream(stream, table)?;
+                    },
+                    20 => { // input_stream
+                        let stream = self.operand(instr, 0)?;
+                        self.streams.borrow_mut().select_replay(stream == 1);
+                    },
+                    21 => { // sound_effect
+                        let mut args = self.operands(&instr.ops)?.into_iter();
+                        let number = args.next().unwrap_or(1);
+                        let effect = args.next().unwrap_or(2);
+                        let [repeats, volume] = args.next().unwrap_or(0x00ff).to_be_bytes();
+                        let routine = args.next().unwrap_or(0);
+
+                        self.sound.borrow_mut().effect(number, effect, volume, repeats, routine);
+                    },
+                    22 => { // read_char
+                        let time = self.operand_or(instr, 1, 0)?;
+                        let routine = self.operand_or(instr, 2, 0)?;
+                        let store = self.read_store(&mut pc)?;
+                        self.set_pc(pc);
+
+                        return Ok(self.request_input(InputRequest::Char(store), time, routine));
+                    },
+                    23 => { // scan_table
+                        let x = self.operand(instr, 0)?;
+                        let table = self.operand(instr, 1)? as usize;
+                        let len = self.operand(instr, 2)? as usize;
+                        // top bit for words, the rest is the entry length
+                        let form = self.operand_or(instr, 3, 0x82)?;
+                        let store = self.read_store(&mut pc)?;
+                        let offset = self.read_offset(&mut pc)?;
+
+                        let found = {
+                            let mem = self.memory.borrow();
+                            let entry_len = (form & 0x7F) as usize;
+                            let mut found = None;
+                            for addr in (0..len).map(|i| table + i * entry_len) {
+                                let val = if form & 0x80 != 0 {
+                                    u16::from(mem.read_word(addr)?)
+                                } else {
+                                    mem.read_byte(addr)? as u16
+                                };
+                                if val == x {
+                                    found = Some(addr);
+                                    break;
+                                }
+                            }
+                            found
+                        };
+
+                        self.store(found.unwrap_or(0) as u16, &store)?;
+                        if found.is_some() == offset.target {
+                            self.branch(offset, &mut pc)?;
+                        }
+                    },
+                    24 => { // not
+                        let val = self.operand(instr, 0)?;
+                        let store = self.read_store(&mut pc)?;
+                        self.store(!val, &store)?;
+                    },
+                    25 | 26 => { // call_vn, call_vn2
+                        if self.call_routine(instr, None, pc)? {
+                            return Ok(ZMachineExecResult::Next);
+                        }
+                    },
+                    27 => { // tokenise
+                        let text = self.operand(instr, 0)?;
+                        let parse = self.operand(instr, 1)?;
+                        let dictionary = self.operand_or(instr, 2, 0)?;
+                        let skip_unknown = self.operand_or(instr, 3, 0)? != 0;
+
+                        let analyzer = ZLexicalAnalyzer::new(text, parse).with_dictionary(dictionary, skip_unknown);
+                        analyzer.run(&mut self.memory.borrow_mut())?;
+                    },
+                    28 => { // encode_text
+                        let text = self.operand(instr, 0)? as usize;
+                        let len = self.operand(instr, 1)? as usize;
+                        let from = self.operand(instr, 2)? as usize;
+                        let coded = self.operand(instr, 3)? as usize;
+
+                        let mut mem = self.memory.borrow_mut();
+                        let version = mem.header().version();
+                        let word: Vec<u8> = (text + from..text + from + len)
+                            .map(|idx| mem.read_byte(idx))
+                            .collect::<Result<_, _>>()?;
+                        for (i, b) in ZDictEntry::from_slice(&word, version).bytes().into_iter().enumerate() {
+                            mem.set_byte(coded + i, b)?;
+                        }
+                    },
+                    29 => { // copy_table
+                        let first = self.operand(instr, 0)? as usize;
+                        let second = self.operand(instr, 1)? as usize;
+                        let size = self.operand(instr, 2)? as i16;
+
+                        let mut mem = self.memory.borrow_mut();
+                        let len = size.unsigned_abs() as usize;
+                        if second == 0 {
+                            for i in 0..len {
+                                mem.set_byte(first + i, 0)?;
+                            }
+                        } else if size < 0 || second < first {
+                            // a negative size asks for a forward copy even if the tables overlap
+                            for i in 0..len {
+                                let b = mem.read_byte(first + i)?;
+                                mem.set_byte(second + i, b)?;
+                            }
+                        } else {
+                            for i in (0..len).rev() {
+                                let b = mem.read_byte(first + i)?;
+                                mem.set_byte(second + i, b)?;
+                            }
+                        }
+                    },
+                    30 => { // print_table
+                        let text = self.operand(instr, 0)? as usize;
+                        let width = self.operand(instr, 1)? as usize;
+                        let height = self.operand_or(instr, 2, 1)? as usize;
+                        let skip = self.operand_or(instr, 3, 0)? as usize;
+
+                        let rows: Vec<String> = {
+                            let mem = self.memory.borrow();
+                            (0..height)
+                                .map(|row| {
+                                    let start = text + row * (width + skip);
+                                    (start..start + width)
+                                        .map(|addr| mem.read_byte(addr).map(|b| b as char))
+                                        .collect::<Result<String, ZMachineError>>()
+                                })
+                                .collect::<Result<_, _>>()?
+                        };
+                        self.print_table(output, &rows);
+                    },
+                    31 => { // check_arg_count
+                        let n = self.operand(instr, 0)? as usize;
+                        let offset = self.read_offset(&mut pc)?;
+                        let cond = {
+                            let stack = self.stack.borrow();
+                            n <= stack[stack.len() - 1].args
+                        };
+
+                        if cond == offset.target {
+                            self.branch(offset, &mut pc)?;
+                        }
+                    },
+                    _code => {
+                        return Err(ZMachineError::UnknownOpcode(instr.opcode));
+                    }
+                }
+            },
+            InstructionType::Extended => {
+                match instr.opcode {
+                    9 => { // save_undo
+                        // restore_undo comes back to the store
+                        let mut stack = self.stack.borrow().clone();
+                        let idx = stack.len() - 1;
+                        stack[idx].pc = pc;
+                        let store = self.read_store(&mut pc)?;
+
+                        if !self.profile.borrow().undo {
+                            self.store(-1i16 as u16, &store)?;
+                        } else if self.undo.borrow().enabled() {
+                            self.push_undo(stack, UndoPoint::SaveUndo);
+                            self.store(1, &store)?;
+                        } else {
+                            self.store(0, &store)?;
+                        }
+                    },
+                    10 => { // restore_undo
+                        let store = self.read_store(&mut pc)?;
+                        if self.restore_undo()? {
+                            return Ok(ZMachineExecResult::Next);
+                        }
+                        self.store(0, &store)?;
                     },
-                    _code => {
-                        println!("Unimplemented variable: instr {:?} pc {:x}", instr, pc);
-                        return ZMachineExecResult::Exit;
+                    _ => {
+                        return Err(ZMachineError::UnknownOpcode(instr.opcode));
                     }
                 }
             }
@@ -936,13 +1999,13 @@ impl ZMachine {
 
         current_frame.pc = pc;
 
-        ZMachineExecResult::Next
+        Ok(ZMachineExecResult::Next)
     }
 
-    fn read_offset(&self, pc: &mut usize) -> BranchOffset {
+    fn read_offset(&self, pc: &mut usize) -> Result<BranchOffset, ZMachineError> {
         let mem = self.memory.borrow();
 
-        let val = mem.read_word(*pc).into();
+        let val = mem.read_word(*pc)?.into();
         let branch_label = BranchLabel::new(val);
 
         let target = branch_label.invert.is_set();
@@ -953,7 +2016,7 @@ impl ZMachine {
         } else {
             *pc += 2; // branch was 2 bytes
             if branch_label.sign.is_set() {
-                (16384 - branch_label.signed_value.value_of()) as i16 * -1
+                -((16384 - branch_label.signed_value.value_of()) as i16)
             } else {
                 branch_label.signed_value.value_of() as i16
             }
@@ -969,104 +2032,1008 @@ impl ZMachine {
             Offset::Signed(offset)
         };
 
-        BranchOffset::new(target, offset)
+        Ok(BranchOffset::new(target, offset))
+    }
+
+    // The first operand is the packed routine, the rest are its arguments.
+    // Calling routine 0 just returns false, so there's no frame to jump into.
+    fn call_routine(&self, instr: &Instruction, ret_addr: Option<Address>, return_pc: usize) -> Result<bool, ZMachineError> {
+        let routine = self.operand(instr, 0)?;
+        let args: Vec<u16> = self.operands(&instr.ops[1..])?;
+        if routine == 0 {
+            if let Some(store) = ret_addr {
+                self.store(0, &store)?;
+            }
+            return Ok(false);
+        }
+
+        let routine_addr = self.memory.borrow().header().routine_address(routine);
+        self.call(routine_addr, &args, ret_addr, return_pc)?;
+        Ok(true)
+    }
+
+    fn call(&self, routine_addr: usize, args: &[u16], ret_addr: Option<Address>, return_pc: usize) -> Result<(), ZMachineError> {
+        let version = self.version();
+        let mem = self.memory.borrow();
+        let n_locals = mem.read_byte(routine_addr)? as usize;
+
+        // V5+ routines have no initial values, their locals start at zero
+        let mut locals: Vec<u16> = vec![0; n_locals];
+        let mut start = routine_addr + 1;
+        if version <= 4 {
+            for (i, local) in locals.iter_mut().enumerate() {
+                *local = mem.read_word(start + i * 2)?.into();
+            }
+            start += n_locals * 2;
+        }
+
+        for (local, arg) in locals.iter_mut().zip(args.iter()) {
+            *local = *arg;
+        }
+
+        let mut stack = self.stack.borrow_mut();
+        let idx = stack.len() - 1;
+        let current_frame = &mut stack[idx];
+        current_frame.pc = return_pc;
+
+        stack.push(StackFrame {
+            locals,
+            stack: Vec::new(),
+            pc: start,
+            ret_addr,
+            args: args.len(),
+            interrupt: false,
+        });
+
+        Ok(())
     }
 
-    fn return_val(&self, val: u16) -> usize {
+    fn return_val(&self, val: u16) -> Result<usize, ZMachineError> {
         let old_frame = {
             let mut stack = self.stack.borrow_mut();
-            stack.pop().expect("blew the stack!")
+            // the outermost frame has nowhere to return to
+            if stack.len() < 2 {
+                return Err(ZMachineError::StackUnderflow);
+            }
+            stack.pop().ok_or(ZMachineError::StackUnderflow)?
         };
-        self.store(val, &old_frame.ret_addr);
+        if let Some(addr) = old_frame.ret_addr.as_ref() {
+            self.store(val, addr)?;
+        }
+        if old_frame.interrupt {
+            self.timer.borrow_mut().returned(val);
+        }
 
-        let stack = self.stack.borrow();
-        let idx = stack.len() - 1;
-        
-        stack[idx].pc
+        Ok(self.get_pc())
     }
 
-    fn branch(&self, offset: BranchOffset, pc: &mut usize) {
+    fn branch(&self, offset: BranchOffset, pc: &mut usize) -> Result<(), ZMachineError> {
         match offset.offset() {
             Offset::RFalse => {
-                let new_pc = self.return_val(0);
+                let new_pc = self.return_val(0)?;
                 *pc = new_pc;
             },
             Offset::RTrue => {
-                let new_pc = self.return_val(1);
+                let new_pc = self.return_val(1)?;
                 *pc = new_pc;
             },
             Offset::Unsigned(off) => {
                 let new_pc = (*pc + *off as usize) - 2;
                 *pc = new_pc;
             },
-            Offset::Signed(mut off) => {
-                if off < 0 {
-                    off = off * -1;
-                    let new_pc = (*pc - off as usize) - 2;
-                    *pc = new_pc;
-                } else {
-                    let new_pc = (*pc + off as usize) - 2;
-                    *pc = new_pc;
+            Offset::Signed(off) => {
+                let new_pc = (*pc as isize + *off as isize) - 2;
+                if new_pc < 0 {
+                    return Err(ZMachineError::OutOfBounds(*pc));
                 }
+                *pc = new_pc as usize;
             }
         }
+
+        Ok(())
     }
 
-    fn store(&self, val: u16, addr: &Address) {
+    fn store(&self, val: u16, addr: &Address) -> Result<(), ZMachineError> {
         let mut mem = self.memory.borrow_mut();
         let mut stack = self.stack.borrow_mut();
         let idx = stack.len() - 1;
         let frame = &mut stack[idx];
         match addr {
             Address::Global(a) => {
-                mem.set_global(*a as usize, val.into());
+                mem.set_global(*a as usize, val.into())?;
             },
             Address::StackPointer => {
                 frame.stack.push(val);
             },
             Address::Local(a) => {
-                frame.locals[*a as usize] = val;
+                let local = frame.locals.get_mut(*a as usize)
+                    .ok_or(ZMachineError::InvalidVariable(addr.addr()))?;
+                *local = val;
             },
             Address::Word(a) => {
-                mem.set_word(*a as usize, val.into());
+                mem.set_word(*a, val.into())?;
             },
             Address::Byte(a) => {
-                mem.set_byte(*a as usize, val as u8);
+                mem.set_byte(*a, val as u8)?;
             }
         }
+
+        Ok(())
+    }
+
+    // Opcodes that name a variable by number read and write the top of the
+    // stack in place rather than popping and pushing it (6.3.4).
+    fn load_indirect(&self, var_num: u16) -> Result<u16, ZMachineError> {
+        if var_num > 0xFF {
+            return Err(ZMachineError::InvalidVariable(var_num));
+        }
+
+        match Address::of(var_num) {
+            Address::StackPointer => {
+                let stack = self.stack.borrow();
+                let idx = stack.len() - 1;
+                stack[idx].stack.last()
+                    .cloned()
+                    .ok_or(ZMachineError::StackUnderflow)
+            },
+            addr => self.get_value(&Operand::Variable(addr)),
+        }
+    }
+
+    fn store_indirect(&self, var_num: u16, val: u16) -> Result<(), ZMachineError> {
+        if var_num > 0xFF {
+            return Err(ZMachineError::InvalidVariable(var_num));
+        }
+
+        match Address::of(var_num) {
+            Address::StackPointer => {
+                let mut stack = self.stack.borrow_mut();
+                let idx = stack.len() - 1;
+                match stack[idx].stack.last_mut() {
+                    Some(top) => *top = val,
+                    None => stack[idx].stack.push(val),
+                }
+                Ok(())
+            },
+            addr => self.store(val, &addr),
+        }
+    }
+
+    fn operand(&self, instr: &Instruction, n: usize) -> Result<u16, ZMachineError> {
+        match instr.ops.get(n) {
+            Some(op) => self.get_value(op),
+            None => Err(ZMachineError::MissingOperand(n)),
+        }
+    }
+
+    // for operands the game is allowed to leave out
+    fn operand_or(&self, instr: &Instruction, n: usize, default: u16) -> Result<u16, ZMachineError> {
+        match instr.ops.get(n) {
+            Some(op) => self.get_value(op),
+            None => Ok(default),
+        }
+    }
+
+    fn operands(&self, ops: &[Operand]) -> Result<Vec<u16>, ZMachineError> {
+        ops.iter().map(|op| self.get_value(op)).collect()
     }
 
-    fn get_value(&self, v: &Operand) -> u16 {
+    fn get_value(&self, v: &Operand) -> Result<u16, ZMachineError> {
         match v {
             Operand::Variable(a) => {
                 match a {
                     Address::Global(addr) => {
                         let mem = self.memory.borrow();
-                        mem.global(*addr as usize).into()
+                        Ok(mem.global(*addr as usize)?.into())
                     },
                     Address::StackPointer => {
                         let mut stack = self.stack.borrow_mut();
                         let idx = stack.len() - 1;
                         let frame = &mut stack[idx];
-                        frame.stack.pop().expect("blew the stack")
+                        frame.stack.pop().ok_or(ZMachineError::StackUnderflow)
                     },
                     Address::Local(addr) => {
-                        let mut stack = self.stack.borrow_mut();
+                        let stack = self.stack.borrow();
                         let idx = stack.len() - 1;
-                        let frame = &mut stack[idx];
-                        frame.locals[*addr as usize]
+                        stack[idx].locals.get(*addr as usize)
+                            .cloned()
+                            .ok_or(ZMachineError::InvalidVariable(a.addr()))
                     },
                     Address::Word(addr) => {
                         let mem = self.memory.borrow();
-                        mem.read_word(*addr as usize).into()
+                        Ok(mem.read_word(*addr)?.into())
                     },
                     Address::Byte(addr) => {
                         let mem = self.memory.borrow();
-                        mem.read_byte(*addr as usize) as u16
+                        Ok(mem.read_byte(*addr)? as u16)
                     }
                 }
             },
-            _ => v.value(),
+            _ => Ok(v.value()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zsound::SoundAction;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn start(code: &[u8]) -> (ZMachine, ZMachineExecResult) {
+        start_version(3, code)
+    }
+
+    fn start_version(version: u8, code: &[u8]) -> (ZMachine, ZMachineExecResult) {
+        start_story(story(version, code), &mut Vec::new())
+    }
+
+    fn start_story(story: Vec<u8>, output: &mut Vec<u8>) -> (ZMachine, ZMachineExecResult) {
+        let mut machine = ZMachine::new();
+        machine.reset(story).unwrap();
+
+        let result = machine.exec(output);
+        (machine, result)
+    }
+
+    fn story(version: u8, code: &[u8]) -> Vec<u8> {
+        let mut story = vec![0u8; 0x400];
+        story[0x00] = version;
+        story[0x06..0x08].copy_from_slice(&0x200u16.to_be_bytes()); // initial pc
+        story[0x0C..0x0E].copy_from_slice(&0x100u16.to_be_bytes()); // globals
+        story[0x0E..0x10].copy_from_slice(&0x300u16.to_be_bytes()); // static memory
+        story[0x200..0x200 + code.len()].copy_from_slice(code);
+        story
+    }
+
+    fn run(code: &[u8]) -> ZMachine {
+        match start(code) {
+            (machine, ZMachineExecResult::Exit) => machine,
+            _ => panic!("test program didn't quit"),
+        }
+    }
+
+    // runs a prepared story to the end, with what it printed
+    fn run_story(story: Vec<u8>) -> (ZMachine, String) {
+        let mut output: Vec<u8> = Vec::new();
+        match start_story(story, &mut output) {
+            (machine, ZMachineExecResult::Exit) => (machine, String::from_utf8(output).unwrap()),
+            _ => panic!("test program didn't quit"),
+        }
+    }
+
+    fn error(code: &[u8]) -> ZMachineError {
+        match start(code) {
+            (_, ZMachineExecResult::Error(e)) => e.error,
+            _ => panic!("test program didn't fail"),
+        }
+    }
+
+    fn stack(machine: &ZMachine) -> Vec<u16> {
+        machine.stack.borrow()[0].stack.clone()
+    }
+
+    // puts the routine at 0x280, packed address 0x140 (0xA0 in V4/5)
+    fn with_routine(code: &[u8], routine: &[u8]) -> Vec<u8> {
+        let mut code = code.to_vec();
+        code.resize(0x80, 0);
+        code.extend(routine);
+        code
+    }
+
+    const PUSH_5: [u8; 3] = [0xE8, 0x7F, 0x05];
+    const PUSH_9: [u8; 3] = [0xE8, 0x7F, 0x09];
+    const QUIT: u8 = 0xBA;
+    // print "hi"
+    const PRINT_HI: [u8; 3] = [0xB2, 0xB5, 0xC5];
+
+    #[test]
+    fn inc_and_dec_work_on_the_top_of_the_stack() {
+        let code = [&PUSH_5[..], &PUSH_9, &[0x95, 0x00, 0x95, 0x00, 0x96, 0x00, QUIT]].concat();
+        let machine = run(&code);
+
+        assert_eq!(stack(&machine), vec![5, 10]);
+    }
+
+    #[test]
+    fn inc_chk_and_dec_chk_leave_the_stack_depth_alone() {
+        // both branch to the next instruction whichever way they go
+        let code = [&PUSH_5[..], &[0x05, 0x00, 0x05, 0xC2, 0x04, 0x00, 0x00, 0xC2, 0x04, 0x00, 0x00, 0xC2, QUIT]].concat();
+        let machine = run(&code);
+
+        assert_eq!(stack(&machine), vec![4]);
+    }
+
+    #[test]
+    fn store_and_load_replace_and_peek_the_top_of_the_stack() {
+        let code = [&PUSH_5[..], &PUSH_9, &[0x0D, 0x00, 0x07, 0x9E, 0x00, 0x10, QUIT]].concat();
+        let machine = run(&code);
+
+        assert_eq!(stack(&machine), vec![5, 7]);
+        assert_eq!(u16::from(machine.memory.borrow().global(0).unwrap()), 7);
+    }
+
+    #[test]
+    fn pull_into_the_stack_overwrites_the_new_top() {
+        let code = [&PUSH_5[..], &PUSH_9, &[0xE9, 0x7F, 0x00, QUIT]].concat();
+        let machine = run(&code);
+
+        assert_eq!(stack(&machine), vec![9]);
+    }
+
+    #[test]
+    fn array_indexes_wrap_around_64k() {
+        // storew 0x182 -1 7, loadw 0x182 -1 -> sp, loadb 0x181 -1 -> sp, storeb 0x182 -1 9
+        let code = [
+            0xE1, 0x07, 0x01, 0x82, 0xFF, 0xFF, 0x07,
+            0xCF, 0x0F, 0x01, 0x82, 0xFF, 0xFF, 0x00,
+            0xD0, 0x0F, 0x01, 0x81, 0xFF, 0xFF, 0x00,
+            0xE2, 0x07, 0x01, 0x82, 0xFF, 0xFF, 0x09,
+            QUIT,
+        ];
+        let machine = run(&code);
+
+        assert_eq!(stack(&machine), vec![7, 0]);
+        assert_eq!(machine.memory.borrow().read_byte(0x181).unwrap(), 9);
+    }
+
+    #[test]
+    fn popping_an_empty_stack_is_an_error() {
+        // ret_popped with nothing pushed
+        assert_eq!(error(&[0xB8]), ZMachineError::StackUnderflow);
+    }
+
+    #[test]
+    fn dividing_by_zero_is_an_error() {
+        // div 1 0 -> sp, mod 1 0 -> sp
+        assert_eq!(error(&[0x17, 0x01, 0x00, 0x00]), ZMachineError::DivideByZero);
+        assert_eq!(error(&[0x18, 0x01, 0x00, 0x00]), ZMachineError::DivideByZero);
+    }
+
+    #[test]
+    fn dividing_the_smallest_number_by_minus_one_wraps() {
+        // div -32768 -1 -> sp, mod -32768 -1 -> sp
+        let code = [0xD7, 0x0F, 0x80, 0x00, 0xFF, 0xFF, 0x00, 0xD8, 0x0F, 0x80, 0x00, 0xFF, 0xFF, 0x00, QUIT];
+        assert_eq!(stack(&run(&code)), vec![0x8000, 0]);
+    }
+
+    #[test]
+    fn unknown_opcodes_report_where_they_are() {
+        // push 5, art_shift 1 2 -> sp
+        match start_version(5, &[&PUSH_5[..], &[0xBE, 0x03, 0x5F, 0x01, 0x02, 0x00, QUIT]].concat()) {
+            (_, ZMachineExecResult::Error(e)) => {
+                assert_eq!(e.error, ZMachineError::UnknownOpcode(3));
+                assert_eq!(e.pc, 0x203);
+                assert_eq!(e.bytes, vec![0xBE, 0x03, 0x5F, 0x01, 0x02]);
+                assert!(matches!(e.form, InstructionType::Extended));
+            },
+            _ => panic!("test program didn't fail"),
+        }
+    }
+
+    #[test]
+    fn object_zero_is_an_error() {
+        // get_parent 0 -> sp
+        assert_eq!(error(&[0x93, 0x00, 0x00]), ZMachineError::InvalidObject(0));
+    }
+
+    #[test]
+    fn restore_goes_back_to_the_branch_of_the_save() {
+        // push 5, save ?L, quit, L: inc g1, restore ?next, quit
+        let code = [&PUSH_5[..], &[0xB5, 0xC3, QUIT, 0x95, 0x11, 0xB6, 0xC2, QUIT]].concat();
+        let (machine, result) = start(&code);
+        let saved = match result {
+            ZMachineExecResult::Save(bytes) => bytes,
+            _ => panic!("test program didn't save"),
+        };
+
+        machine.send_save_result(true).unwrap();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Restore));
+        assert_eq!(global(&machine, 1), 1);
+
+        machine.send_restore(Some(&saved)).unwrap();
+        assert_eq!(global(&machine, 1), 0);
+        assert_eq!(stack(&machine), vec![5]);
+
+        // back after the save's branch, so g1 goes up again before the next restore
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Restore));
+        assert_eq!(global(&machine, 1), 1);
+        machine.send_restore(None).unwrap();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+    }
+
+    #[test]
+    fn branching_save_and_restore_are_version_3_only() {
+        for version in [4, 5] {
+            for opcode in [0xB5, 0xB6] {
+                match start_version(version, &[opcode, 0xC2, QUIT]) {
+                    (_, ZMachineExecResult::Error(e)) => assert_eq!(e.error, ZMachineError::UnknownOpcode(opcode & 0x0F)),
+                    _ => panic!("test program didn't fail"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_stories_warn_and_fail_verify() {
+        // verify ?quit, push 1
+        let mut story = story(3, &[0xBD, 0xC5, 0xE8, 0x7F, 0x01, QUIT]);
+        let sum = story[0x40..].iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+        story[0x1C..0x1E].copy_from_slice(&sum.to_be_bytes());
+
+        let mut machine = ZMachine::new();
+        assert_eq!(machine.load_bytes(story.clone()).unwrap(), vec![]);
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+        assert_eq!(stack(&machine), vec![]);
+
+        story[0x3FF] = 0x55;
+        let warnings = machine.load_bytes(story).unwrap();
+        assert_eq!(warnings, vec![LoadWarning::ChecksumMismatch { expected: sum, actual: sum.wrapping_add(0x55) }]);
+
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+        assert_eq!(stack(&machine), vec![1]);
+    }
+
+    #[test]
+    fn restarting_without_a_story_does_nothing() {
+        ZMachine::new().restart();
+    }
+
+    #[test]
+    fn restart_reloads_the_story_but_keeps_the_flags_2_bits() {
+        // show_status, inc g1, storew 0x10 0 3, restart
+        let (machine, result) = start(&[0xBC, 0x95, 0x11, 0xE1, 0x57, 0x10, 0x00, 0x03, 0xB7]);
+        assert!(matches!(result, ZMachineExecResult::ShowStatus(_)));
+        let mut expected = machine.memory.borrow().dynamic().to_vec();
+        expected[0x11] |= 3;
+
+        // stops at the show_status again, straight after the initial pc
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::ShowStatus(_)));
+        assert_eq!(machine.get_pc(), machine.header().unwrap().initial_pc() + 1);
+        assert_eq!(machine.memory.borrow().dynamic(), &expected[..]);
+        assert_eq!(global(&machine, 1), 0);
+        assert_eq!(machine.stack.borrow().len(), 1);
+    }
+
+    #[test]
+    fn a_machine_without_a_story_can_still_be_asked_things() {
+        let machine = ZMachine::new();
+        match machine.exec(&mut Vec::new()) {
+            ZMachineExecResult::Error(e) => assert_eq!(e.error, ZMachineError::NoStory),
+            _ => panic!("nothing to run"),
+        }
+
+        assert!(machine.header().is_none());
+        assert_eq!(machine.terminating_keys(), vec![]);
+        assert_eq!(machine.preloaded_input(), "");
+        assert_eq!(machine.checksum(), 0);
+        assert!(machine.send_input("look").is_ok());
+        assert!(machine.send_char('y').is_ok());
+        assert!(machine.send_save_result(true).is_ok());
+        assert!(machine.send_restore(None).is_ok());
+        machine.send_timeout();
+        machine.set_transcript(None);
+        machine.update_header(|header| header.set_transcripting(true));
+    }
+
+    #[test]
+    fn routines_start_with_their_initial_locals() {
+        // add local0 local1 -> sp, ret_popped
+        let routine = [0x02, 0x00, 0x11, 0x00, 0x22, 0x74, 0x01, 0x02, 0x00, 0xB8];
+        // call_2s R 7 -> sp, call_2n R 7
+        let code = [0xD9, 0x1F, 0x01, 0x40, 0x07, 0x00, 0xDA, 0x1F, 0x01, 0x40, 0x07, QUIT];
+
+        assert_eq!(stack(&run(&with_routine(&code, &routine))), vec![0x29]);
+    }
+
+    #[test]
+    fn v5_routines_start_with_zeroed_locals() {
+        // add local0 local1 -> sp, ret_popped
+        let routine = [0x02, 0x74, 0x01, 0x02, 0x00, 0xB8];
+        // call_vs R 5 -> sp, call_1n R
+        let code = [0xE0, 0x1F, 0x00, 0xA0, 0x05, 0x00, 0x8F, 0x00, 0xA0, QUIT];
+
+        match start_version(5, &with_routine(&code, &routine)) {
+            (machine, ZMachineExecResult::Exit) => assert_eq!(stack(&machine), vec![5]),
+            _ => panic!("test program didn't quit"),
+        }
+    }
+
+    #[test]
+    fn check_arg_count_sees_the_supplied_arguments() {
+        // check_arg_count 2 ?rtrue, rfalse
+        let routine = [0x02, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x7F, 0x02, 0xC1, 0xB1];
+        // call_vs R 5 -> sp, call_vs R 5 6 -> sp
+        let code = [0xE0, 0x1F, 0x01, 0x40, 0x05, 0x00, 0xE0, 0x17, 0x01, 0x40, 0x05, 0x06, 0x00, QUIT];
+
+        assert_eq!(stack(&run(&with_routine(&code, &routine))), vec![0, 1]);
+    }
+
+    #[test]
+    fn v5_not_is_a_variable_opcode() {
+        // not 5 -> sp
+        match start_version(5, &[0xF8, 0x7F, 0x05, 0x00, QUIT]) {
+            (machine, ZMachineExecResult::Exit) => assert_eq!(stack(&machine), vec![0xFFFA]),
+            _ => panic!("test program didn't quit"),
+        }
+    }
+
+    #[test]
+    fn v5_print_paddr_unpacks_by_four() {
+        // print_paddr 0xA0
+        let mut story = story(5, &[0x8D, 0x00, 0xA0, QUIT]);
+        // "hi"
+        story[0x280..0x282].copy_from_slice(&[0xB5, 0xC5]);
+
+        let (_, output) = run_story(story);
+        assert_eq!(output, "hi");
+    }
+
+    #[test]
+    fn v8_routines_can_live_past_64k() {
+        // call_vs 0x2000 -> sp, quit
+        let mut story = story(8, &[0xE0, 0x3F, 0x20, 0x00, 0x00, QUIT]);
+        // ret 7
+        story.resize(0x10000, 0);
+        story.extend(&[0x00, 0x9B, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        story[0x1A..0x1C].copy_from_slice(&((0x10008 / 8) as u16).to_be_bytes()); // file length
+
+        let mut machine = ZMachine::new();
+        machine.load_bytes(story).unwrap();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+        assert_eq!(stack(&machine), vec![7]);
+    }
+
+    // (number, action, volume, repeats)
+    type SoundCall = (u16, SoundAction, u8, u8);
+
+    #[derive(Clone, Default)]
+    struct TestSound(Rc<RefCell<Vec<SoundCall>>>);
+
+    impl ZSound for TestSound {
+        fn sound_effect(&mut self, number: u16, action: SoundAction, volume: u8, repeats: u8) {
+            self.0.borrow_mut().push((number, action, volume, repeats));
+        }
+    }
+
+    #[test]
+    fn sound_effect_splits_repeats_and_volume() {
+        // sound_effect 3 2 0x05C8, sound_effect 3 3
+        let code = [0xF5, 0x53, 0x03, 0x02, 0x05, 0xC8, 0xF5, 0x5F, 0x03, 0x03, QUIT];
+        let sound = TestSound::default();
+
+        let mut machine = ZMachine::new();
+        machine.reset(story(5, &code)).unwrap();
+        machine.set_sound(Some(Box::new(sound.clone())));
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+
+        assert_eq!(*sound.0.borrow(), vec![(3, SoundAction::Start, 200, 5), (3, SoundAction::Stop, 255, 0)]);
+    }
+
+    #[test]
+    fn finished_sounds_run_their_routine_without_a_backend() {
+        // inc g1, rtrue
+        let routine = [0x00, 0x95, 0x11, 0xB0];
+        // sound_effect 3 2 0x0108 R, read_char 1 -> sp
+        let code = [0xF5, 0x50, 0x03, 0x02, 0x01, 0x08, 0x00, 0xA0, 0xF6, 0x7F, 0x01, 0x00, QUIT];
+        let (machine, result) = start_version(5, &with_routine(&code, &routine));
+        assert!(matches!(result, ZMachineExecResult::NeedChar));
+
+        machine.sound_finished();
+        machine.send_char('a').unwrap();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+        assert_eq!(global(&machine, 1), 1);
+        assert_eq!(stack(&machine), vec![97]);
+    }
+
+    struct TestClock(Rc<Cell<Duration>>);
+
+    impl ZClock for TestClock {
+        fn now(&self) -> Duration {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn read_char_stores_the_key() {
+        // read_char 1 -> sp
+        let (machine, result) = start_version(5, &[0xF6, 0x7F, 0x01, 0x00, QUIT]);
+        assert!(matches!(result, ZMachineExecResult::NeedChar));
+
+        machine.send_char('a').unwrap();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+        assert_eq!(stack(&machine), vec![97]);
+    }
+
+    // a writer the test can still read after handing it over
+    #[derive(Clone, Default)]
+    struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedLog {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn the_upper_window_keeps_its_own_text() {
+        // split_window 2, set_window 1, print "hi", set_window 0, print "hi", show_status, split_window 2
+        let code = [
+            &[0xEA, 0x7F, 0x02, 0xEB, 0x7F, 0x01][..], &PRINT_HI,
+            &[0xEB, 0x7F, 0x00], &PRINT_HI,
+            &[0xBC, 0xEA, 0x7F, 0x02, QUIT],
+        ].concat();
+        let mut output: Vec<u8> = Vec::new();
+        let (machine, result) = start_story(story(3, &code), &mut output);
+
+        assert!(matches!(result, ZMachineExecResult::ShowStatus(_)));
+        assert_eq!(String::from_utf8(output).unwrap(), "hi");
+        assert_eq!(machine.upper_window(), vec!["hi", ""]);
+
+        // splitting again in V3 clears it
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+        assert_eq!(machine.upper_window(), vec!["", ""]);
+    }
+
+    #[test]
+    fn output_stream_3_captures_text_into_a_table() {
+        // output_stream 3 0x180, print "hi", output_stream -3, print "hi"
+        let code = [&[0xF3, 0x4F, 0x03, 0x01, 0x80][..], &PRINT_HI, &[0xF3, 0x3F, 0xFF, 0xFD], &PRINT_HI, &[QUIT]].concat();
+        let (machine, output) = run_story(story(3, &code));
+
+        assert_eq!(output, "hi");
+        let mem = machine.memory.borrow();
+        assert_eq!(&mem.slice(0x180)[..4], &[0, 2, b'h', b'i']);
+    }
+
+    #[test]
+    fn output_stream_3_needs_a_table() {
+        // output_stream 3
+        assert_eq!(error(&[0xF3, 0x7F, 0x03]), ZMachineError::MissingOperand(1));
+    }
+
+    #[test]
+    fn flags_2_turns_the_transcript_on_and_off() {
+        // print "hi", storew 0x10 0 0, print "hi", storew 0x10 0 1, print "hi"
+        let code = [
+            &PRINT_HI[..], &[0xE1, 0x57, 0x10, 0x00, 0x00],
+            &PRINT_HI, &[0xE1, 0x57, 0x10, 0x00, 0x01],
+            &PRINT_HI, &[QUIT],
+        ].concat();
+        let log = SharedLog::default();
+
+        let mut machine = ZMachine::new();
+        machine.reset(story(3, &code)).unwrap();
+        machine.set_transcript(Some(Box::new(log.clone())));
+        let mut output: Vec<u8> = Vec::new();
+        assert!(matches!(machine.exec(&mut output), ZMachineExecResult::Exit));
+
+        assert_eq!(String::from_utf8(output).unwrap(), "hihihi");
+        assert_eq!(log.text(), "hihi");
+    }
+
+    #[test]
+    fn output_stream_4_records_only_while_selected() {
+        // output_stream -4, aread 0x180 0 -> sp, output_stream 4, aread 0x180 0 -> sp
+        let aread = [0xE4, 0x1F, 0x01, 0x80, 0x00, 0x00];
+        let code = [&[0xF3, 0x3F, 0xFF, 0xFC][..], &aread, &[0xF3, 0x7F, 0x04], &aread, &[QUIT]].concat();
+        let mut story = story(5, &code);
+        story[0x180] = 20;
+        let log = SharedLog::default();
+
+        let mut machine = ZMachine::new();
+        machine.reset(story).unwrap();
+        machine.set_command_log(Some(Box::new(log.clone())));
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::NeedInput));
+        machine.send_input("west").unwrap();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::NeedInput));
+        machine.send_input("east").unwrap();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+
+        assert_eq!(log.text(), "east\n");
+    }
+
+    #[test]
+    fn replayed_commands_answer_read_char_and_read() {
+        // read_char 1 -> sp, aread 0x180 0 -> sp
+        let mut story = story(5, &[0xF6, 0x7F, 0x01, 0x00, 0xE4, 0x1F, 0x01, 0x80, 0x00, 0x00, QUIT]);
+        story[0x180] = 20;
+
+        let mut machine = ZMachine::new();
+        machine.reset(story).unwrap();
+        machine.set_command_source(Some(Box::new(&b"x\nlook\n"[..])));
+
+        let mut output: Vec<u8> = Vec::new();
+        assert!(matches!(machine.exec(&mut output), ZMachineExecResult::Exit));
+        assert_eq!(String::from_utf8(output).unwrap(), "look\n");
+        assert_eq!(stack(&machine), vec![b'x' as u16, 13]);
+
+        let mem = machine.memory.borrow();
+        assert_eq!(mem.read_byte(0x181).unwrap(), 4);
+        assert_eq!(&mem.slice(0x182)[..4], b"look");
+    }
+
+    #[test]
+    fn input_stream_switches_between_the_keyboard_and_a_command_file() {
+        // input_stream 0, aread, input_stream 1, aread, aread with each aread 0x180 0 -> sp
+        let aread = [0xE4, 0x1F, 0x01, 0x80, 0x00, 0x00];
+        let code = [&[0xF4, 0x7F, 0x00][..], &aread, &[0xF4, 0x7F, 0x01], &aread, &aread, &[QUIT]].concat();
+        let mut story = story(5, &code);
+        story[0x180] = 20;
+        let text = |machine: &ZMachine| {
+            let mem = machine.memory.borrow();
+            let len = mem.read_byte(0x181).unwrap() as usize;
+            String::from_utf8(mem.slice(0x182)[..len].to_vec()).unwrap()
+        };
+
+        let mut machine = ZMachine::new();
+        machine.reset(story).unwrap();
+        machine.set_command_source(Some(Box::new(&b"north\n"[..])));
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::NeedInput));
+
+        // the file only has one line, after that it's back to the keyboard
+        machine.send_input("west").unwrap();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::NeedInput));
+        assert_eq!(text(&machine), "north");
+
+        machine.send_input("east").unwrap();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+        assert_eq!(text(&machine), "east");
+    }
+
+    #[test]
+    fn timeouts_run_the_interrupt_routine() {
+        // inc g0, je g0 2 ?rtrue, rfalse
+        let routine = [0x00, 0x95, 0x10, 0x41, 0x10, 0x02, 0xC1, 0xB1];
+        // read_char 1 10 R -> sp
+        let code = [0xF6, 0x53, 0x01, 0x0A, 0x00, 0xA0, 0x00, QUIT];
+
+        let now = Rc::new(Cell::new(Duration::from_secs(100)));
+        let mut machine = ZMachine::new();
+        machine.set_clock(Box::new(TestClock(now.clone())));
+        machine.reset(story(5, &with_routine(&code, &routine))).unwrap();
+
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::NeedTimedChar(10)));
+        now.set(Duration::from_millis(100_400));
+        assert_eq!(machine.time_left(), Some(Duration::from_millis(600)));
+
+        // the first run returns false and the wait starts over
+        machine.send_timeout();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::NeedTimedChar(10)));
+        assert_eq!(machine.time_left(), Some(Duration::from_secs(1)));
+
+        // the second returns true and ends the input
+        machine.send_timeout();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+        assert_eq!(stack(&machine), vec![0]);
+        assert_eq!(u16::from(machine.memory.borrow().global(0).unwrap()), 2);
+    }
+
+    // aread 0x180 0x1A0 -> sp, with a dictionary of "look" and "north" at 0x300
+    fn read_story() -> Vec<u8> {
+        let mut story = story(5, &[0xE4, 0x0F, 0x01, 0x80, 0x01, 0xA0, 0x00, QUIT]);
+        story[0x08..0x0A].copy_from_slice(&0x300u16.to_be_bytes());
+        story[0x300..0x305].copy_from_slice(&[1, b',', 7, 0, 2]);
+        story[0x305..0x30B].copy_from_slice(&ZDictEntry::from_slice(b"look", 5).bytes());
+        story[0x30C..0x312].copy_from_slice(&ZDictEntry::from_slice(b"north", 5).bytes());
+        story[0x180] = 20;
+        story[0x1A0] = 4;
+        story
+    }
+
+    fn read(story: Vec<u8>, input: &str, key: u8) -> ZMachine {
+        let (machine, result) = start_story(story, &mut Vec::new());
+        assert!(matches!(result, ZMachineExecResult::NeedInput));
+
+        machine.send_terminated_input(input, key).unwrap();
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+        machine
+    }
+
+    #[test]
+    fn v5_read_keeps_the_length_and_stores_the_terminator() {
+        let machine = read(read_story(), "Look, north\n", 13);
+        let mem = machine.memory.borrow();
+
+        assert_eq!(stack(&machine), vec![13]);
+        assert_eq!(mem.read_byte(0x181).unwrap(), 11);
+        assert_eq!(&mem.slice(0x182)[..11], b"look, north");
+
+        // (dictionary entry, length, position) for look , north
+        let parse: Vec<u8> = mem.slice(0x1A1)[..13].to_vec();
+        assert_eq!(parse, vec![3, 0x03, 0x05, 4, 2, 0, 0, 1, 6, 0x03, 0x0C, 5, 8]);
+    }
+
+    #[test]
+    fn v5_read_can_be_preloaded_and_ended_by_a_function_key() {
+        let mut story = read_story();
+        story[0x181] = 3;
+        story[0x182..0x185].copy_from_slice(b"nor");
+        story[0x1A0] = 1;
+        story[0x2E..0x30].copy_from_slice(&0x1C0u16.to_be_bytes());
+        story[0x1C0] = 255;
+
+        let (machine, _) = start_story(story.clone(), &mut Vec::new());
+        assert_eq!(machine.preloaded_input(), "nor");
+        assert!(machine.terminating_keys().contains(&130));
+
+        let machine = read(story, "north look", 130);
+        let mem = machine.memory.borrow();
+        assert_eq!(stack(&machine), vec![130]);
+        assert_eq!(&mem.slice(0x1A1)[..5], &[1, 0x03, 0x0C, 5, 2]);
+    }
+
+    #[test]
+    fn tokenise_can_use_an_unsorted_user_dictionary() {
+        // tokenise 0x180 0x1A0 0x340 1
+        let mut story = read_story();
+        story[0x200..0x20B].copy_from_slice(&[0xFB, 0x01, 0x01, 0x80, 0x01, 0xA0, 0x03, 0x40, 0x01, QUIT, 0]);
+        story[0x181] = 10;
+        story[0x182..0x18C].copy_from_slice(b"look north");
+        story[0x1A2..0x1AA].fill(0xAA);
+        story[0x340..0x344].copy_from_slice(&[0, 6, 0xFF, 0xFF]);
+        story[0x344..0x34A].copy_from_slice(&ZDictEntry::from_slice(b"north", 5).bytes());
+
+        let (machine, _) = run_story(story);
+
+        // look isn't in the user dictionary, so its entry is left alone
+        let mem = machine.memory.borrow();
+        assert_eq!(&mem.slice(0x1A1)[..9], &[2, 0xAA, 0xAA, 0xAA, 0xAA, 0x03, 0x44, 5, 7]);
+    }
+
+    #[test]
+    fn encode_text_writes_a_dictionary_word() {
+        // encode_text 0x1C0 4 1 0x1D0
+        let mut story = story(5, &[0xFC, 0x14, 0x01, 0xC0, 0x04, 0x01, 0x01, 0xD0, QUIT]);
+        story[0x1C0..0x1C6].copy_from_slice(b"xlookx");
+
+        let (machine, _) = run_story(story);
+
+        let mem = machine.memory.borrow();
+        // l o o, k 5 5, 5 5 5 with the end bit
+        assert_eq!(&mem.slice(0x1D0)[..6], &[0x46, 0x94, 0x40, 0xA5, 0x94, 0xA5]);
+    }
+
+    #[test]
+    fn copy_table_handles_overlapping_tables() {
+        // copy_table 0x180 0x181 4, copy_table 0x190 0x191 -4
+        let code = [0xFD, 0x07, 0x01, 0x80, 0x01, 0x81, 0x04, 0xFD, 0x03, 0x01, 0x90, 0x01, 0x91, 0xFF, 0xFC, QUIT];
+        let mut story = story(5, &code);
+        story[0x180..0x185].copy_from_slice(&[1, 2, 3, 4, 5]);
+        story[0x190..0x195].copy_from_slice(&[1, 2, 3, 4, 5]);
+
+        let (machine, _) = run_story(story);
+
+        // a negative size copies forwards and smears the first byte along
+        let mem = machine.memory.borrow();
+        assert_eq!(&mem.slice(0x180)[..5], &[1, 1, 2, 3, 4]);
+        assert_eq!(&mem.slice(0x190)[..5], &[1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn scan_table_finds_words_and_bytes() {
+        // scan_table 20 0x1A0 3 -> sp, scan_table 30 0x1A0 6 1 -> sp, scan_table 99 0x1A0 3 -> sp
+        let code = [
+            0xF7, 0x47, 0x14, 0x01, 0xA0, 0x03, 0x00, 0xC2,
+            0xF7, 0x45, 0x1E, 0x01, 0xA0, 0x06, 0x01, 0x00, 0xC2,
+            0xF7, 0x47, 0x63, 0x01, 0xA0, 0x03, 0x00, 0xC2,
+            QUIT,
+        ];
+        let mut story = story(5, &code);
+        story[0x1A0..0x1A6].copy_from_slice(&[0, 10, 0, 20, 0, 30]);
+
+        let (machine, _) = run_story(story);
+        assert_eq!(stack(&machine), vec![0x1A2, 0x1A5, 0]);
+    }
+
+    #[test]
+    fn print_table_prints_rows_with_a_skip() {
+        // print_table 0x1C0 3 2 1
+        let mut story = story(5, &[0xFE, 0x15, 0x01, 0xC0, 0x03, 0x02, 0x01, QUIT]);
+        story[0x1C0..0x1C8].copy_from_slice(b"abcXdefX");
+
+        let (_, output) = run_story(story);
+        assert_eq!(output, "abc\ndef");
+    }
+
+    #[test]
+    fn copy_table_cant_write_to_static_memory() {
+        // copy_table 0x180 0x300 2
+        assert_eq!(error(&[0xFD, 0x07, 0x01, 0x80, 0x03, 0x00, 0x02]), ZMachineError::ReadOnly(0x301));
+    }
+
+    fn global(machine: &ZMachine, n: usize) -> u16 {
+        u16::from(machine.memory.borrow().global(n).unwrap())
+    }
+
+    #[test]
+    fn restore_undo_returns_2_from_save_undo() {
+        // save_undo -> g0, je g0 2 ?quit, inc g1, restore_undo -> g2, quit
+        let code = [0xBE, 0x09, 0xFF, 0x10, 0x41, 0x10, 0x02, 0xC8, 0x95, 0x11, 0xBE, 0x0A, 0xFF, 0x12, QUIT];
+        let (machine, result) = start_version(5, &code);
+
+        assert!(matches!(result, ZMachineExecResult::Exit));
+        assert_eq!((global(&machine, 0), global(&machine, 1)), (2, 0));
+    }
+
+    #[test]
+    fn restore_undo_fails_with_nothing_saved() {
+        // restore_undo -> sp
+        let (machine, _) = start_version(5, &[0xBE, 0x0A, 0xFF, 0x00, QUIT]);
+        assert_eq!(stack(&machine), vec![0]);
+    }
+
+    #[test]
+    fn undo_goes_back_to_a_checkpoint() {
+        // inc g1
+        let mut machine = ZMachine::new();
+        machine.reset(story(3, &[0x95, 0x11, QUIT])).unwrap();
+        machine.checkpoint();
+        machine.exec(&mut Vec::new());
+        assert_eq!(global(&machine, 1), 1);
+
+        assert!(machine.undo());
+        assert_eq!(global(&machine, 1), 0);
+        assert!(!machine.undo());
+
+        assert!(matches!(machine.exec(&mut Vec::new()), ZMachineExecResult::Exit));
+        assert_eq!(global(&machine, 1), 1);
+    }
+
+    #[test]
+    fn restarting_forgets_the_undo_states() {
+        let mut machine = ZMachine::new();
+        machine.checkpoint();
+        assert!(!machine.undo());
+
+        machine.reset(story(3, &[QUIT])).unwrap();
+        machine.checkpoint();
+        machine.restart();
+        assert!(!machine.undo());
+    }
+
+    #[test]
+    fn random_can_return_its_upper_bound() {
+        let mut rng = ZRng::default();
+        let rolls: Vec<u16> = (0..200).map(|_| rng.next(2)).collect();
+
+        assert!(rolls.iter().all(|r| (1..=2).contains(r)));
+        assert!(rolls.contains(&2));
+    }
+
+    #[test]
+    fn small_negative_seeds_count() {
+        // random -3 -> sp, then random 10 -> sp four times
+        let roll = [0xE7, 0x7F, 0x0A, 0x00];
+        let mut code = vec![0xE7, 0x3F, 0xFF, 0xFD, 0x00];
+        for _ in 0..4 {
+            code.extend(&roll);
         }
+        code.push(QUIT);
+
+        assert_eq!(stack(&run(&code)), vec![0, 1, 2, 3, 1]);
+    }
+
+    #[test]
+    fn a_host_seed_repeats() {
+        let mut first = ZRng::default();
+        let mut second = ZRng::default();
+        first.seed_with(42);
+        second.seed_with(42);
+
+        let first: Vec<u16> = (0..20).map(|_| first.next(100)).collect();
+        let second: Vec<u16> = (0..20).map(|_| second.next(100)).collect();
+        assert_eq!(first, second);
     }
 }
@@ -1,8 +1,8 @@
 use crate::bits::ZWord;
+use crate::zerror::ZMachineError;
+use crate::zheader::ZHeader;
 use crate::zstr::ZString;
-use typenum::{U0, U3, U5};
 use std::convert::AsRef;
-use std::convert::TryInto;
 
 pub struct ZGlobals<T: AsRef<[u8]>> {
     table: T
@@ -13,343 +13,372 @@ impl<T: AsRef<[u8]>> ZGlobals<T> {
         ZGlobals { table }
     }
 
-    fn get(&self, idx: usize) -> ZWord {
+    fn get(&self, idx: usize) -> Option<ZWord> {
         let table = self.table.as_ref();
 
         let idx = idx * 2;
-        (table[idx], table[idx + 1]).into()
+        Some((*table.get(idx)?, *table.get(idx + 1)?).into())
     }
 
 }
 
 impl <T: AsRef<[u8]> + AsMut<[u8]>> ZGlobals<T> {
-    fn set(&mut self, idx: usize, val: ZWord) {
+    fn set(&mut self, idx: usize, val: ZWord) -> Option<()> {
         let table = self.table.as_mut();
         let idx = idx * 2;
 
         let (hi, lo) = val.into();
-        table[idx] = hi;
-        table[idx + 1] = lo;
+        *table.get_mut(idx)? = hi;
+        *table.get_mut(idx + 1)? = lo;
+        Some(())
     }
 }
 
+// V1-3 entries are 9 bytes with 32 attributes and one byte object numbers,
+// V4+ entries are 14 bytes with 48 attributes and two byte numbers.
 pub(crate) struct ZObjectEntry<'a, T: 'a + AsRef<[u8]>> {
-    attributes: u32,
+    version: u8,
+    attributes: u64,
     properties: u16,
     data: T,
     _lifetime: std::marker::PhantomData<&'a T>
 }
 
 impl<'a, T: 'a + AsRef<[u8]>> ZObjectEntry<'a, T> {
-    fn new(d: T) -> ZObjectEntry<'a, T> {
+    fn new(d: T, version: u8) -> ZObjectEntry<'a, T> {
         let data = d.as_ref();
-        let attributes = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-        let properties: u16 = u16::from_be_bytes([data[7], data[8]]);
+        let mut attr_bytes = [0u8; 8];
+        let n_attr_bytes = ZObjectEntry::<T>::attr_bytes(version);
+        attr_bytes[..n_attr_bytes].copy_from_slice(&data[..n_attr_bytes]);
+
+        let attributes = u64::from_be_bytes(attr_bytes);
+        let idx = data.len() - 2;
+        let properties: u16 = u16::from_be_bytes([data[idx], data[idx + 1]]);
 
         ZObjectEntry {
+            version,
             attributes,
-            properties: properties,
+            properties,
             data: d,
             _lifetime: std::marker::PhantomData
         }
     }
 
-    fn parent_num(&self) -> Option<u8> {
-        let data = self.data.as_ref();
-        let num = data[4];
-        if num != 0 {
-            Some(num)
-        } else {
-            None
-        }
+    fn attr_bytes(version: u8) -> usize {
+        if version <= 3 { 4 } else { 6 }
     }
 
-    fn child_num(&self) -> Option<u8> {
+    // parent, sibling and child follow the attributes in that order
+    fn relative(&self, n: usize) -> Option<u16> {
         let data = self.data.as_ref();
-        let num = data[6];
-        if num != 0 {
-            Some(num)
+        let num = if self.version <= 3 {
+            data[4 + n] as u16
         } else {
-            None
-        }
-    }
+            let idx = 6 + n * 2;
+            u16::from_be_bytes([data[idx], data[idx + 1]])
+        };
 
-    fn sibling_num(&self) -> Option<u8> {
-        let data = self.data.as_ref();
-        let num = data[5];
         if num != 0 {
             Some(num)
         } else {
             None
         }
     }
+
+    fn parent_num(&self) -> Option<u16> {
+        self.relative(0)
+    }
+
+    fn sibling_num(&self) -> Option<u16> {
+        self.relative(1)
+    }
+
+    fn child_num(&self) -> Option<u16> {
+        self.relative(2)
+    }
+
+    fn has_attr(&self, attr_num: u16) -> bool {
+        self.attributes & (0x8000_0000_0000_0000 >> attr_num as u64) != 0
+    }
 }
 
 impl<'a, T: 'a + AsRef<[u8]> + AsMut<[u8]>> ZObjectEntry<'a, T> {
-    fn set_parent(&mut self, parent_num: u8) {
+    fn set_relative(&mut self, n: usize, num: u16) {
+        let version = self.version;
         let data = self.data.as_mut();
-        data[4] = parent_num;
+        if version <= 3 {
+            data[4 + n] = num as u8;
+        } else {
+            let idx = 6 + n * 2;
+            data[idx..idx + 2].copy_from_slice(&num.to_be_bytes());
+        }
     }
 
-    fn set_child(&mut self, child_num: u8) {
-        let data = self.data.as_mut();
-        data[6] = child_num;
+    fn set_parent(&mut self, parent_num: u16) {
+        self.set_relative(0, parent_num);
     }
 
-    fn set_sibling(&mut self, sibling_num: u8) {
-        let data = self.data.as_mut();
-        data[5] = sibling_num;
+    fn set_sibling(&mut self, sibling_num: u16) {
+        self.set_relative(1, sibling_num);
     }
 
-    fn set_attr(&mut self, attr_num: u8) {
-        self.attributes = self.attributes | (0x80000000 >> attr_num as u32);
-        let bytes = self.attributes.to_be_bytes();
-        let data = self.data.as_mut();
-        data[0] = bytes[0];
-        data[1] = bytes[1];
-        data[2] = bytes[2];
-        data[3] = bytes[3];
+    fn set_child(&mut self, child_num: u16) {
+        self.set_relative(2, child_num);
     }
 
-    fn clear_attr(&mut self, attr_num: u8) {
-        self.attributes = self.attributes & !(0x80000000 >> attr_num as u32);
+    fn write_attributes(&mut self) {
+        let n = ZObjectEntry::<T>::attr_bytes(self.version);
         let bytes = self.attributes.to_be_bytes();
-        let data = self.data.as_mut();
-        data[0] = bytes[0];
-        data[1] = bytes[1];
-        data[2] = bytes[2];
-        data[3] = bytes[3];
+        self.data.as_mut()[..n].copy_from_slice(&bytes[..n]);
+    }
+
+    fn set_attr(&mut self, attr_num: u16) {
+        self.attributes |= 0x8000_0000_0000_0000 >> attr_num as u64;
+        self.write_attributes();
     }
-}
 
-bitstruct! {
-    PropertySize: u8 {
-        size: PropSize, Width = U3, Offset = U5,
-        number: PropNum, Width = U5, Offset = U0
+    fn clear_attr(&mut self, attr_num: u16) {
+        self.attributes &= !(0x8000_0000_0000_0000 >> attr_num as u64);
+        self.write_attributes();
     }
 }
 
-struct ZObjectProperty<'a, T: AsRef<[u8]>> {
-    size: PropertySize,
-    data: T,
-    _lifetime: std::marker::PhantomData<&'a T>
+// The size byte(s) in front of each property. In V1-3 a single byte holds
+// the number in the bottom 5 bits and the length - 1 in the top 3. V4+ uses
+// 6 bits for the number, and when the top bit is set a second byte follows
+// with the length, where a length of 0 means 64.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PropertyHeader {
+    number: u8,
+    len: usize,
+    header_len: usize,
 }
 
+impl PropertyHeader {
+    fn parse(props: &[u8], version: u8) -> Option<PropertyHeader> {
+        let first = *props.first()?;
+        if version <= 3 {
+            return Some(PropertyHeader {
+                number: first & 0x1F,
+                len: (first >> 5) as usize + 1,
+                header_len: 1,
+            });
+        }
 
-impl<'a, T: AsRef<[u8]>> ZObjectProperty<'a, T> {
-    fn new(size: u8, data: T) -> ZObjectProperty<'a, T> {
-        ZObjectProperty {
-            data,
-            size: PropertySize::new(size),
-            _lifetime: std::marker::PhantomData,
+        let number = first & 0x3F;
+        if first & 0x80 != 0 {
+            Some(PropertyHeader { number, len: PropertyHeader::long_len(*props.get(1)?), header_len: 2 })
+        } else {
+            Some(PropertyHeader { number, len: if first & 0x40 != 0 { 2 } else { 1 }, header_len: 1 })
         }
     }
 
-    fn get(&self) -> u16 {
-        let size = self.size.size.value_of();
-        let data = self.data.as_ref();
+    fn long_len(size: u8) -> usize {
+        match size & 0x3F {
+            0 => 64,
+            len => len as usize,
+        }
+    }
 
-        if size == 0 {
-            let lo = data[0];
-            u16::from_be_bytes([0, lo])
+    // Works back from the byte just before the property data. In V4+ that's
+    // either a one byte header or the second byte of a two byte one, which
+    // always has its top bit set.
+    fn len_before(size: u8, version: u8) -> usize {
+        if version <= 3 {
+            (size >> 5) as usize + 1
+        } else if size & 0x80 != 0 {
+            PropertyHeader::long_len(size)
+        } else if size & 0x40 != 0 {
+            2
         } else {
-            let hi = data[0];
-            let lo = data[1];
-            u16::from_be_bytes([hi, lo])
+            1
         }
     }
-}
 
-impl<'a, T: AsRef<[u8]> + AsMut<[u8]>> ZObjectProperty<'a, T> {
-    fn put(&mut self, val: u16) {
-        let [hi, lo] = val.to_be_bytes();
-        let data = self.data.as_mut();
-        if self.size.size.value_of() == 0 {
-            data[0] = lo;
-        } else { // there's another condition here?
-            data[0] = hi;
-            data[1] = lo;
-        }
+    fn is_end(&self) -> bool {
+        self.number == 0
+    }
+
+    fn size(&self) -> usize {
+        self.header_len + self.len
     }
 }
 
 pub(crate) struct ZObjectProps<'a, T: AsRef<[u8]>> {
     props: T,
+    version: u8,
     _lifetime: std::marker::PhantomData<&'a T>
 }
 
 impl<'a, T: AsRef<[u8]>> ZObjectProps<'a, T> {
-    fn new(props: T) -> ZObjectProps<'a, T> {
-        ZObjectProps { props, _lifetime: std::marker::PhantomData }
+    fn new(props: T, version: u8) -> ZObjectProps<'a, T> {
+        ZObjectProps { props, version, _lifetime: std::marker::PhantomData }
     }
 
-    fn get(self, num: u8) -> Option<u16> {
+    // index of the property's header and the header itself
+    fn find(&self, num: u8) -> Option<(usize, PropertyHeader)> {
         let props = self.props.as_ref();
         let mut idx = 0;
         loop {
-            let prop_size = PropertySize::new(props[idx]);
-            let prop_num = prop_size.number.value_of();
+            let header = PropertyHeader::parse(props.get(idx..)?, self.version)?;
 
-            if prop_num == num {
-                let prop = ZObjectProperty::new(props[idx], &props[idx + 1..]);
-                return Some(prop.get());
-            } else if prop_size.get() == 0 {
+            if header.is_end() {
                 return None;
+            } else if header.number == num {
+                return Some((idx, header));
             }
-            idx += prop_size.size.value_of() as usize + 2;
+            idx += header.size();
         }
     }
 
-    fn idx_of(self, num: u8) -> Option<u16> {
+    fn get(self, num: u8) -> Option<u16> {
         let props = self.props.as_ref();
-        let mut idx = 0;
-        loop {
-            let prop_size = PropertySize::new(props[idx]);
-            let prop_num = prop_size.number.value_of();
+        let (idx, header) = self.find(num)?;
+        let data = props.get(idx + header.header_len..)?;
 
-            if prop_num == num {
-                return Some(idx as u16 + 1); // skip size byte
-            } else if prop_size.get() == 0 {
-                return None;
-            }
-            idx += prop_size.size.value_of() as usize + 2;
+        if header.len == 1 {
+            Some(*data.first()? as u16)
+        } else {
+            Some(u16::from_be_bytes([*data.first()?, *data.get(1)?]))
         }
     }
 
+    // offset of the property's data
+    fn idx_of(self, num: u8) -> Option<u16> {
+        self.find(num).map(|(idx, header)| (idx + header.header_len) as u16)
+    }
+
     fn after(self, num: u8) -> Option<u8> {
         let props = self.props.as_ref();
-        let mut idx = 0;
 
-        if num == 0 {
-            let prop = PropertySize::new(props[idx]);
-            if prop.get() == 0 {
-                return None;
-            }
-            return Some(prop.number.value_of());
-        }
+        let idx = if num == 0 {
+            0
+        } else {
+            let (idx, header) = self.find(num)?;
+            idx + header.size()
+        };
 
-        loop {
-            let prop_size = PropertySize::new(props[idx]);
-            let prop_num = prop_size.number.value_of();
-
-            if prop_num == num {
-                idx += prop_size.size.value_of() as usize + 2;
-                let next_prop = PropertySize::new(props[idx]);
-                return Some(next_prop.number.value_of());
-            } else if prop_size.get() == 0 {
-                return None;
-            }
-            idx += prop_size.size.value_of() as usize + 2;
-        }
+        PropertyHeader::parse(props.get(idx..)?, self.version)
+            .map(|header| header.number)
     }
 }
 
 impl<'a, T: AsRef<[u8]> + AsMut<[u8]>> ZObjectProps<'a, T> {
-    fn put(mut self, num: u8, val: ZWord) {
-        let props = self.props.as_mut();
-        let mut idx = 0;
-        loop {
-            let prop_size = PropertySize::new(props[idx]);
-            let prop_num = prop_size.number.value_of();
-
-            if prop_num == num {
-                let mut prop = ZObjectProperty::new(props[idx], &mut props[idx + 1..]);
-                prop.put(val.into());
-                return;
-            } else if prop_size.get() == 0 {
-                return;
-            }
-            idx += prop_size.size.value_of() as usize + 2;
+    fn put(mut self, num: u8, val: ZWord) -> Option<()> {
+        let (idx, header) = self.find(num)?;
+        let [hi, lo] = u16::from(val).to_be_bytes();
+        let data = self.props.as_mut().get_mut(idx + header.header_len..)?;
+
+        if header.len == 1 {
+            *data.first_mut()? = lo;
+        } else {
+            data.get_mut(..2)?.copy_from_slice(&[hi, lo]);
         }
+        Some(())
     }
 }
 
 pub(crate) struct ZObjectTable<'a, T: 'a + AsRef<[u8]>> {
     table: T,
+    version: u8,
     _lifetime: std::marker::PhantomData<&'a T>
 }
 
 impl<'a, T: 'a + AsRef<[u8]>> ZObjectTable<'a, T> {
-    fn new(table: T) -> ZObjectTable<'a, T> {
-        ZObjectTable { table, _lifetime: std::marker::PhantomData }
+    fn new(table: T, version: u8) -> ZObjectTable<'a, T> {
+        ZObjectTable { table, version, _lifetime: std::marker::PhantomData }
     }
 
-    fn get_object(&self, obj: u8) -> ZObjectEntry<'a, &[u8]> {
-        if obj == 0 {
-            panic!("read from obj 0");
-        } else {
-            let table = self.table.as_ref();
-            let objects = &table[62..]; // skip defaults
+    fn n_defaults(&self) -> usize {
+        if self.version <= 3 { 31 } else { 63 }
+    }
 
-            let obj_idx = (obj as usize - 1) * 9; //entries are 9 bytes
+    fn entry_len(&self) -> usize {
+        if self.version <= 3 { 9 } else { 14 }
+    }
 
-            ZObjectEntry::new(&objects[obj_idx..obj_idx + 9])
+    fn entry_idx(&self, obj: u16) -> Result<usize, ZMachineError> {
+        if obj == 0 || (self.version <= 3 && obj > 255) {
+            return Err(ZMachineError::InvalidObject(obj));
         }
+
+        // skip the defaults
+        Ok(self.n_defaults() * 2 + (obj as usize - 1) * self.entry_len())
     }
 
-    fn get_prop_default(&self, prop_num: u8) -> u16 {
-        if prop_num == 0 {
-            panic!("tried to read property 0");
+    fn get_object(&self, obj: u16) -> Result<ZObjectEntry<'a, &[u8]>, ZMachineError> {
+        let idx = self.entry_idx(obj)?;
+
+        self.table.as_ref()
+            .get(idx..idx + self.entry_len())
+            .map(|data| ZObjectEntry::new(data, self.version))
+            .ok_or(ZMachineError::InvalidObject(obj))
+    }
+
+    fn get_prop_default(&self, prop_num: u8) -> Option<u16> {
+        if prop_num == 0 || prop_num as usize > self.n_defaults() {
+            return None;
         }
         let table = self.table.as_ref();
         let prop_idx = (prop_num - 1) as usize * 2;
-        let hi = table[prop_idx];
-        let lo = table[prop_idx + 1];
+        let hi = *table.get(prop_idx)?;
+        let lo = *table.get(prop_idx + 1)?;
 
-        u16::from_be_bytes([hi, lo])
+        Some(u16::from_be_bytes([hi, lo]))
     }
 
 }
 
 impl<'a, T: 'a + AsRef<[u8]> + AsMut<[u8]>> ZObjectTable<'a, T> {
-    fn get_object_mut(&mut self, obj: u8) -> ZObjectEntry<'a, &mut [u8]> {
-        if obj == 0 {
-            panic!("reading from obj 0")
-        } else {
-            let table = self.table.as_mut();
-            let objects = &mut table[62..];
-
-            let obj_idx = (obj as usize - 1) * 9; //entries are 9 bytes
+    fn get_object_mut(&mut self, obj: u16) -> Result<ZObjectEntry<'a, &mut [u8]>, ZMachineError> {
+        let idx = self.entry_idx(obj)?;
+        let (len, version) = (self.entry_len(), self.version);
 
-            ZObjectEntry::new(&mut objects[obj_idx..obj_idx + 9])
-        }
+        self.table.as_mut()
+            .get_mut(idx..idx + len)
+            .map(|data| ZObjectEntry::new(data, version))
+            .ok_or(ZMachineError::InvalidObject(obj))
     }
 }
 
 pub(crate) struct ZDictionary {
     addr: usize,
     entry_len: usize,
-    entries: Vec<u32>,
+    entries: Vec<Vec<u8>>,
     separators: Vec<u8>,
 }
 
 impl ZDictionary {
-    pub fn new(mem: &[u8], addr: usize) -> ZDictionary {
-        let sep_len = mem[0];
+    // entries are looked up by their encoded text, 4 bytes in V1-3 and 6 after
+    pub fn new(mem: &[u8], addr: usize, version: u8) -> Result<ZDictionary, ZMachineError> {
+        let key_len = if version <= 3 { 4 } else { 6 };
+        let bytes = |idx: usize, len: usize| mem.get(idx..idx + len).ok_or(ZMachineError::OutOfBounds(idx));
 
-        let separators: Vec<u8> = (0..sep_len)
-            .map(|i| { mem[i as usize + 1] })
-            .collect();
+        let sep_len = bytes(addr, 1)?[0] as usize;
+        let separators = bytes(addr + 1, sep_len)?.to_vec();
 
-        let mut idx = sep_len as usize + 1;
-        let entry_len = mem[idx]; idx += 1;
-        let n_entries = u16::from_be_bytes([mem[idx], mem[idx + 1]]) as usize;
-        idx += 2;
-        let entries: Vec<u32> = (0..n_entries * entry_len as usize)
-            .step_by(entry_len as usize)
-            .map(|i| { u32::from_be_bytes(mem[idx + i..idx + i + 4].try_into().unwrap()) })
-            .collect();
+        let mut idx = addr + 1 + sep_len;
+        let header = bytes(idx, 3)?;
+        let entry_len = (header[0] as usize).max(key_len);
+        // user dictionaries give a negative count when they aren't sorted,
+        // which makes no difference to a linear search
+        let n_entries = i16::from_be_bytes([header[1], header[2]]).unsigned_abs() as usize;
+        idx += 3;
 
-        ZDictionary { addr: addr + idx, entry_len: entry_len as usize, separators, entries }
+        let entries = (0..n_entries)
+            .map(|i| bytes(idx + i * entry_len, key_len).map(|key| key.to_vec()))
+            .collect::<Result<Vec<Vec<u8>>, ZMachineError>>()?;
+
+        Ok(ZDictionary { addr: idx, entry_len, separators, entries })
     }
 
     pub fn separators(&self) -> &Vec<u8> {
         &self.separators
     }
 
-    pub fn lookup(&self, word: &u32) -> Option<usize> {
+    pub fn lookup(&self, word: &[u8]) -> Option<usize> {
         self.entries.iter()
-            .position(|e| *e == *word)
+            .position(|e| e == word)
             .map(|idx| {
                 self.addr + (idx * self.entry_len)
             })
@@ -362,112 +391,127 @@ pub(crate) struct ZMemory {
     globals_idx: usize,
     objects_idx: usize,
     abbrev_idx: usize,
-    dictionary_idx: usize
+    dictionary_idx: usize,
+    static_idx: usize,
 }
 
 impl ZMemory {
 
-    pub(crate) fn read_word(&self, idx: usize) -> ZWord {
-        (self.bytes[idx], self.bytes[idx + 1]).into()
+    pub(crate) fn read_word(&self, idx: usize) -> Result<ZWord, ZMachineError> {
+        match self.bytes.get(idx..idx + 2) {
+            Some(word) => Ok((word[0], word[1]).into()),
+            None => Err(ZMachineError::OutOfBounds(idx)),
+        }
     }
 
-    pub(crate) fn read_byte(&self, idx: usize) -> u8 {
-        self.bytes[idx]
+    pub(crate) fn read_byte(&self, idx: usize) -> Result<u8, ZMachineError> {
+        self.bytes.get(idx)
+            .cloned()
+            .ok_or(ZMachineError::OutOfBounds(idx))
     }
 
-    pub(crate) fn reset(&mut self, data: Vec<u8>) {
-        //println!("Resetting mem, len: {}", data.len());
-        self.bytes = data;
+    pub(crate) fn reset(&mut self, data: Vec<u8>) -> Result<(), ZMachineError> {
+        let header = ZHeader::new(&data[..]).ok_or(ZMachineError::OutOfBounds(data.len()))?;
+        let check = |addr: usize| {
+            if addr >= data.len() {
+                Err(ZMachineError::OutOfBounds(addr))
+            } else {
+                Ok(addr)
+            }
+        };
 
-        let globals_idx = self.read_word(0x0C);
-        self.globals_idx = u16::from(globals_idx) as usize;
-        //println!("globals at {:x}", self.globals_idx);
+        self.globals_idx = check(header.globals())?;
+        self.objects_idx = check(header.objects())?;
+        self.abbrev_idx = check(header.abbreviations())?;
+        self.dictionary_idx = check(header.dictionary())?;
+        self.static_idx = check(header.static_memory())?;
 
-        let objects_idx = self.read_word(0x0A);
-        self.objects_idx = u16::from(objects_idx) as usize;
-        //println!("objects at {:x}", self.objects_idx);
+        self.bytes = data;
+        Ok(())
+    }
 
-        let abbrev_idx = self.read_word(0x18);
-        self.abbrev_idx = u16::from(abbrev_idx) as usize;
-        //println!("abbreviations table at {:x}", self.abbrev_idx);
+    fn version(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    fn objects(&self) -> ZObjectTable<'_, &[u8]> {
+        ZObjectTable::new(&self.bytes[self.objects_idx..], self.version())
+    }
 
-        let dictionary_idx = self.read_word(0x08);
-        self.dictionary_idx = u16::from(dictionary_idx) as usize;
-        //println!("dictionary table at {:x}", self.dictionary_idx);
+    fn objects_mut(&mut self) -> ZObjectTable<'_, &mut [u8]> {
+        let version = self.version();
+        ZObjectTable::new(&mut self.bytes[self.objects_idx..], version)
     }
 
-    fn set_object_parent(&mut self, obj_num: u8, new_parent: u8) {
-        //println!("setting obj {} parent to {}", obj_num, new_parent);
-        ZObjectTable::new(&mut self.bytes[self.objects_idx..])
-            .get_object_mut(obj_num)
+    fn set_object_parent(&mut self, obj_num: u16, new_parent: u16) -> Result<(), ZMachineError> {
+        self.objects_mut()
+            .get_object_mut(obj_num)?
             .set_parent(new_parent);
+        Ok(())
     }
 
-    fn set_object_child(&mut self, obj_num: u8, new_child: u8) {
-        //println!("setting obj {} child to {}", obj_num, new_child);
-        ZObjectTable::new(&mut self.bytes[self.objects_idx..])
-            .get_object_mut(obj_num)
+    fn set_object_child(&mut self, obj_num: u16, new_child: u16) -> Result<(), ZMachineError> {
+        self.objects_mut()
+            .get_object_mut(obj_num)?
             .set_child(new_child);
+        Ok(())
     }
 
-    fn set_object_sibling(&mut self, obj_num: u8, new_sibling: u8) {
-        ZObjectTable::new(&mut self.bytes[self.objects_idx..])
-            .get_object_mut(obj_num)
+    fn set_object_sibling(&mut self, obj_num: u16, new_sibling: u16) -> Result<(), ZMachineError> {
+        self.objects_mut()
+            .get_object_mut(obj_num)?
             .set_sibling(new_sibling);
+        Ok(())
     }
 
-    pub(crate) fn get_object_parent(&self, obj_num: u8) -> u8 {
-        ZObjectTable::new(&self.bytes[self.objects_idx..])
-            .get_object(obj_num)
+    pub(crate) fn get_object_parent(&self, obj_num: u16) -> Result<u16, ZMachineError> {
+        Ok(self.objects()
+            .get_object(obj_num)?
             .parent_num()
-            .unwrap_or(0)
+            .unwrap_or(0))
     }
 
-    pub(crate) fn get_object_child(&self, obj_num: u8) -> Option<u8> {
-        ZObjectTable::new(&self.bytes[self.objects_idx..])
-            .get_object(obj_num)
-            .child_num()
+    pub(crate) fn get_object_child(&self, obj_num: u16) -> Result<Option<u16>, ZMachineError> {
+        Ok(self.objects()
+            .get_object(obj_num)?
+            .child_num())
     }
 
-    pub(crate) fn get_object_sibling(&self, obj_num: u8) -> Option<u8> {
-        ZObjectTable::new(&self.bytes[self.objects_idx..])
-            .get_object(obj_num)
-            .sibling_num()
+    pub(crate) fn get_object_sibling(&self, obj_num: u16) -> Result<Option<u16>, ZMachineError> {
+        Ok(self.objects()
+            .get_object(obj_num)?
+            .sibling_num())
     }
 
-    pub(crate) fn get_object_name(&self, obj_num: u8) -> Option<String> {
-        if obj_num == 0 {
-            None
-        } else {
-            let address: u16 = ZObjectTable::new(&self.bytes[self.objects_idx..])
-                .get_object(obj_num)
-                .properties.into();
-            let (name, _) = self.read_string(address as usize + 1);
+    pub(crate) fn get_object_name(&self, obj_num: u16) -> Result<String, ZMachineError> {
+        let address = self.objects()
+            .get_object(obj_num)?
+            .properties;
+        let (name, _) = self.read_string(address as usize + 1)?;
 
-            Some(name)
-        }
+        Ok(name)
     }
 
-    pub(crate) fn remove_obj(&mut self, obj: u8) {
-        let parent = self.get_object_parent(obj);
-        self.set_object_parent(obj, 0);
+    pub(crate) fn remove_obj(&mut self, obj: u16) -> Result<(), ZMachineError> {
+        let parent = self.get_object_parent(obj)?;
+        self.set_object_parent(obj, 0)?;
 
         if parent == 0 {
-            return;
+            return Ok(());
         }
 
-        if let Some(child) = self.get_object_child(parent) {
+        if let Some(child) = self.get_object_child(parent)? {
             if obj == child {
-                let sib = self.get_object_sibling(obj).unwrap_or(0);
-                self.set_object_child(parent, sib);
-                self.set_object_sibling(obj, 0);
-            } else if child != 0 { // shouldn't need to check for 0?
+                let sib = self.get_object_sibling(obj)?.unwrap_or(0);
+                self.set_object_child(parent, sib)?;
+                self.set_object_sibling(obj, 0)?;
+            } else {
                 let mut current = child;
-                while let Some(sib) = self.get_object_sibling(current) {
+                while let Some(sib) = self.get_object_sibling(current)? {
                     if sib == obj {
-                        let new_sib = self.get_object_sibling(sib).unwrap_or(0);
-                        self.set_object_sibling(current, new_sib);
-                        self.set_object_sibling(obj, 0);
+                        let new_sib = self.get_object_sibling(sib)?.unwrap_or(0);
+                        self.set_object_sibling(current, new_sib)?;
+                        self.set_object_sibling(obj, 0)?;
                         current = new_sib;
                     } else {
                         current = sib;
@@ -478,156 +522,307 @@ impl ZMemory {
                 }
             }
         }
+
+        Ok(())
     }
 
-    pub(crate) fn insert_object(&mut self, obj: u8, dest: u8) {
-        self.remove_obj(obj);
-        let prev_child = self.get_object_child(dest);
-        self.set_object_parent(obj, dest);
+    pub(crate) fn insert_object(&mut self, obj: u16, dest: u16) -> Result<(), ZMachineError> {
+        self.remove_obj(obj)?;
+        let prev_child = self.get_object_child(dest)?;
+        self.set_object_parent(obj, dest)?;
         if let Some(sib) = prev_child {
-            self.set_object_sibling(obj, sib);
+            self.set_object_sibling(obj, sib)?;
         }
-        self.set_object_child(dest, obj);
+        self.set_object_child(dest, obj)
     }
 
-    pub(crate) fn test_attr(&self, obj_num: u8, attr: u8) -> bool {
+    fn check_attr(&self, attr: u16) -> Result<(), ZMachineError> {
+        let n_attrs = if self.version() <= 3 { 32 } else { 48 };
+        if attr >= n_attrs {
+            return Err(ZMachineError::InvalidAttribute(attr));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn test_attr(&self, obj_num: u16, attr: u16) -> Result<bool, ZMachineError> {
+        self.check_attr(attr)?;
         if obj_num == 0 {
-            return false;
+            return Ok(false);
         }
-        let table = ZObjectTable::new(&self.bytes[self.objects_idx..]);
-        let obj = table.get_object(obj_num);
 
-        let ret = obj.attributes & (0x80000000 >> attr as u32) > 0;
-        ret
+        Ok(self.objects().get_object(obj_num)?.has_attr(attr))
     }
 
-    pub(crate) fn set_attr(&mut self, obj_num: u8, attr: u8) {
-        ZObjectTable::new(&mut self.bytes[self.objects_idx..])
-            .get_object_mut(obj_num)
+    pub(crate) fn set_attr(&mut self, obj_num: u16, attr: u16) -> Result<(), ZMachineError> {
+        self.check_attr(attr)?;
+        self.objects_mut()
+            .get_object_mut(obj_num)?
             .set_attr(attr);
+        Ok(())
     }
 
-    pub(crate) fn clear_attr(&mut self, obj_num: u8, attr: u8) {
-        ZObjectTable::new(&mut self.bytes[self.objects_idx..])
-            .get_object_mut(obj_num)
+    pub(crate) fn clear_attr(&mut self, obj_num: u16, attr: u16) -> Result<(), ZMachineError> {
+        self.check_attr(attr)?;
+        self.objects_mut()
+            .get_object_mut(obj_num)?
             .clear_attr(attr);
+        Ok(())
     }
 
-    pub(crate) fn put_prop(&mut self, obj_num: u8, prop_num: u8, val: ZWord) {
-        let address: u16 = ZObjectTable::new(&mut self.bytes[self.objects_idx..])
-            .get_object(obj_num)
-            .properties.into();
-        let n_header = self.bytes[address as usize] as u16 * 2;
-        let addr = address as usize + n_header as usize + 1;
+    // address of the first property, just past the short name
+    fn props_addr(&self, obj_num: u16) -> Result<usize, ZMachineError> {
+        let address = self.objects()
+            .get_object(obj_num)?
+            .properties as usize;
+        let n_header = self.read_byte(address)? as usize * 2;
 
-        let property = ZObjectProps::new(&mut self.bytes[addr..]);
+        let addr = address + n_header + 1;
+        if addr >= self.bytes.len() {
+            return Err(ZMachineError::OutOfBounds(addr));
+        }
 
-        property.put(prop_num, val);
+        Ok(addr)
     }
 
-    pub(crate) fn get_prop(&self, obj_num: u8, prop_num: u8) -> ZWord {
-        let address: u16 = ZObjectTable::new(&self.bytes[self.objects_idx..])
-            .get_object(obj_num)
-            .properties.into();
+    fn prop_num(&self, obj_num: u16, prop_num: u16) -> Result<u8, ZMachineError> {
+        let max = if self.version() <= 3 { 31 } else { 63 };
+        if prop_num > max {
+            return Err(ZMachineError::InvalidProperty { object: obj_num, property: prop_num });
+        }
 
-        let n_header = self.bytes[address as usize] as usize * 2;
-        let addr = address as usize + n_header + 1;
+        Ok(prop_num as u8)
+    }
 
-        let property = ZObjectProps::new(&self.bytes[addr..]);
+    pub(crate) fn put_prop(&mut self, obj_num: u16, prop_num: u16, val: ZWord) -> Result<(), ZMachineError> {
+        let addr = self.props_addr(obj_num)?;
+        let num = self.prop_num(obj_num, prop_num)?;
+        let version = self.version();
 
-        if let Some(p) = property.get(prop_num) {
-            p.into()
-        } else {
-            ZObjectTable::new(&self.bytes[self.objects_idx..])
-                .get_prop_default(prop_num).into()
-        }
+        ZObjectProps::new(&mut self.bytes[addr..], version)
+            .put(num, val)
+            .ok_or(ZMachineError::InvalidProperty { object: obj_num, property: prop_num })
     }
 
-    pub(crate) fn get_next_prop(&self, obj_num: u8, prop_num: u8) -> u8 {
-        let address: u16 = ZObjectTable::new(&self.bytes[self.objects_idx..])
-            .get_object(obj_num)
-            .properties.into();
-        let n_header = self.bytes[address as usize] as usize * 2;
-        let addr = address as usize + n_header + 1;
+    pub(crate) fn get_prop(&self, obj_num: u16, prop_num: u16) -> Result<ZWord, ZMachineError> {
+        let addr = self.props_addr(obj_num)?;
+        let num = self.prop_num(obj_num, prop_num)?;
 
-        let property = ZObjectProps::new(&self.bytes[addr..]);
+        ZObjectProps::new(&self.bytes[addr..], self.version())
+            .get(num)
+            .or_else(|| self.objects().get_prop_default(num))
+            .map(ZWord::from)
+            .ok_or(ZMachineError::InvalidProperty { object: obj_num, property: prop_num })
+    }
+
+    pub(crate) fn get_next_prop(&self, obj_num: u16, prop_num: u16) -> Result<u8, ZMachineError> {
+        let addr = self.props_addr(obj_num)?;
+        let num = self.prop_num(obj_num, prop_num)?;
 
-        property.after(prop_num).expect("Couldn't get next property")
+        ZObjectProps::new(&self.bytes[addr..], self.version())
+            .after(num)
+            .ok_or(ZMachineError::InvalidProperty { object: obj_num, property: prop_num })
     }
 
-    pub(crate) fn get_prop_addr(&self, obj_num: u8, prop_num: u8) -> u16 {
-        let address: u16 = ZObjectTable::new(&self.bytes[self.objects_idx..])
-            .get_object(obj_num)
-            .properties.into();
-        let n_header = self.bytes[address as usize] as usize * 2;
-        let addr = address as usize + n_header + 1;
+    pub(crate) fn get_prop_addr(&self, obj_num: u16, prop_num: u16) -> Result<u16, ZMachineError> {
+        let addr = self.props_addr(obj_num)?;
+        let num = self.prop_num(obj_num, prop_num)?;
 
-        let property = ZObjectProps::new(&self.bytes[addr..]);
+        let idx = ZObjectProps::new(&self.bytes[addr..], self.version()).idx_of(num);
 
-        addr as u16 + property.idx_of(prop_num).unwrap_or(0)
+        Ok(idx.map(|idx| addr as u16 + idx).unwrap_or(0))
     }
 
-    pub(crate) fn get_prop_len(&self, prop_addr: u16) -> u16 {
+    pub(crate) fn get_prop_len(&self, prop_addr: u16) -> Result<u16, ZMachineError> {
         if prop_addr == 0 {
-            0
+            Ok(0)
         } else {
-            let size_byte = self.bytes[prop_addr as usize - 1]; // size byte!
-            let prop_size = PropertySize::new(size_byte);
+            let size_byte = self.read_byte(prop_addr as usize - 1)?; // size byte!
 
-            let size = prop_size.size.value_of() as u16 + 1;
-            size
+            Ok(PropertyHeader::len_before(size_byte, self.version()) as u16)
         }
     }
 
-    pub(crate) fn read_string(&self, addr: usize) -> (String, usize) {
-        let zstr = ZString::new(&self.bytes[..], addr as usize, &self.bytes[self.abbrev_idx..]);
+    pub(crate) fn read_string(&self, addr: usize) -> Result<(String, usize), ZMachineError> {
+        if addr >= self.bytes.len() {
+            return Err(ZMachineError::OutOfBounds(addr));
+        }
+
+        let zstr = ZString::new(&self.bytes[..], addr, &self.bytes[self.abbrev_idx..]);
         let offset = zstr.offset();
 
-        (zstr.string(), offset)
+        Ok((zstr.string(), offset))
     }
 
-    pub(crate) fn write_text(&mut self, addr: u16, text: &str) {
-        let start_addr = addr as usize + 1;
-        let mut end: usize = 0;
-        for (i, c) in text.chars().enumerate() {
-            self.set_byte(start_addr + i, (c as u8).to_ascii_lowercase());
-            end = i;
+    // Byte 0 of a text buffer holds its size. V1-4 keep the text from byte 1
+    // with a zero after it, V5+ put the length in byte 1 and the text after.
+    pub(crate) fn write_text(&mut self, addr: u16, text: &str) -> Result<(), ZMachineError> {
+        let addr = addr as usize;
+        let version = self.header().version();
+        let size = self.read_byte(addr)? as usize;
+        let max = if version <= 4 { size.saturating_sub(1) } else { size };
+
+        let text: Vec<u8> = text.trim_end_matches(&['\r', '\n'][..])
+            .chars()
+            .map(|c| if c.is_ascii() && !c.is_ascii_control() { (c as u8).to_ascii_lowercase() } else { b'?' })
+            .take(max)
+            .collect();
+
+        let start = if version <= 4 { addr + 1 } else { addr + 2 };
+        for (i, c) in text.iter().enumerate() {
+            self.set_byte(start + i, *c)?;
         }
-        self.set_byte(start_addr + end, 0);
+
+        if version <= 4 {
+            self.set_byte(start + text.len(), 0)
+        } else {
+            self.set_byte(addr + 1, text.len() as u8)
+        }
+    }
+
+    // (where it starts, the text) for a buffer laid out as above
+    pub(crate) fn read_text(&self, addr: u16) -> Result<(usize, Vec<u8>), ZMachineError> {
+        let addr = addr as usize;
+        if self.header().version() <= 4 {
+            let text = self.slice(addr + 1).iter().take_while(|c| **c != 0).cloned().collect();
+            Ok((addr + 1, text))
+        } else {
+            let len = self.read_byte(addr + 1)? as usize;
+            match self.bytes.get(addr + 2..addr + 2 + len) {
+                Some(text) => Ok((addr + 2, text.to_vec())),
+                None => Err(ZMachineError::OutOfBounds(addr + 2 + len)),
+            }
+        }
+    }
+
+    pub(crate) fn dictionary(&self) -> Result<ZDictionary, ZMachineError> {
+        self.dictionary_at(self.dictionary_idx)
+    }
+
+    pub(crate) fn dictionary_at(&self, addr: usize) -> Result<ZDictionary, ZMachineError> {
+        ZDictionary::new(&self.bytes, addr, self.header().version())
+    }
+
+    pub(crate) fn header(&self) -> ZHeader<&[u8]> {
+        ZHeader::new(&self.bytes[..]).expect("memory is at least a header long")
     }
 
-    pub(crate) fn dictionary(&self) -> ZDictionary {
-        let dict = &self.bytes[self.dictionary_idx..];
-        ZDictionary::new(dict, self.dictionary_idx)
+    pub(crate) fn header_mut(&mut self) -> ZHeader<&mut [u8]> {
+        ZHeader::new(&mut self.bytes[..]).expect("memory is at least a header long")
     }
 
-    pub(crate) fn header(&self) -> &[u8] {
-        &self.bytes[0..64]
+    pub(crate) fn static_base(&self) -> usize {
+        self.static_idx
     }
 
-    pub(crate) fn global(&self, idx: usize) -> ZWord {
+    pub(crate) fn dynamic(&self) -> &[u8] {
+        &self.bytes[..self.static_idx]
+    }
+
+    pub(crate) fn restore_dynamic(&mut self, data: &[u8]) {
+        // the transcript and fixed pitch bits survive a restore or restart
+        let flags2 = self.bytes[0x11] & 0x03;
+
+        let len = self.static_idx;
+        self.bytes[..len].copy_from_slice(&data[..len]);
+        self.bytes[0x11] = (self.bytes[0x11] & !0x03) | flags2;
+    }
+
+    pub(crate) fn global(&self, idx: usize) -> Result<ZWord, ZMachineError> {
         ZGlobals::new(&self.bytes[self.globals_idx..])
             .get(idx)
+            .ok_or(ZMachineError::OutOfBounds(self.globals_idx + idx * 2))
     }
 
-    pub(crate) fn set_global(&mut self, idx: usize, val: ZWord) {
+    pub(crate) fn set_global(&mut self, idx: usize, val: ZWord) -> Result<(), ZMachineError> {
+        let addr = self.globals_idx + idx * 2;
+        if addr + 1 >= self.static_idx {
+            return Err(ZMachineError::ReadOnly(addr));
+        }
+
         ZGlobals::new(&mut self.bytes[self.globals_idx..])
-            .set(idx, val);
+            .set(idx, val)
+            .ok_or(ZMachineError::OutOfBounds(addr))
     }
 
-    pub(crate) fn set_word(&mut self, idx: usize, val: ZWord) {
+    // games may only write to dynamic memory
+    pub(crate) fn set_word(&mut self, idx: usize, val: ZWord) -> Result<(), ZMachineError> {
+        if idx + 1 >= self.static_idx {
+            return Err(ZMachineError::ReadOnly(idx));
+        }
         let (hi, lo) = val.into();
 
         self.bytes[idx] = hi;
         self.bytes[idx + 1] = lo;
+        Ok(())
     }
 
-    pub(crate) fn set_byte(&mut self, idx: usize, val: u8) {
+    pub(crate) fn set_byte(&mut self, idx: usize, val: u8) -> Result<(), ZMachineError> {
+        if idx >= self.static_idx {
+            return Err(ZMachineError::ReadOnly(idx));
+        }
+
         self.bytes[idx] = val;
+        Ok(())
     }
 
     pub(crate) fn slice(&self, idx: usize) -> &[u8] {
-        &self.bytes[idx..]
+        self.bytes.get(idx..).unwrap_or(&[])
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // objects at 0x100 in a V4 story, object 2 is inside object 1 and has
+    // a short property 5 and a 64 byte property 40
+    fn v4_memory() -> ZMemory {
+        let mut data = vec![0u8; 0x400];
+        data[0x00] = 4;
+        data[0x0A..0x0C].copy_from_slice(&0x100u16.to_be_bytes());
+        data[0x0E..0x10].copy_from_slice(&0x300u16.to_be_bytes());
+
+        let entries = 0x100 + 63 * 2;
+        let second = entries + 14;
+        data[entries + 10..entries + 12].copy_from_slice(&2u16.to_be_bytes()); // child
+        data[entries + 12..entries + 14].copy_from_slice(&0x200u16.to_be_bytes());
+        data[second + 6..second + 8].copy_from_slice(&1u16.to_be_bytes()); // parent
+        data[second + 12..second + 14].copy_from_slice(&0x280u16.to_be_bytes());
+
+        // neither object has a name
+        data[0x280 + 1] = 0x80 | 40;
+        data[0x280 + 2] = 0x80; // length 0 means 64
+        data[0x280 + 3 + 64] = 0x40 | 5;
+        data[0x280 + 3 + 64 + 1..0x280 + 3 + 64 + 3].copy_from_slice(&0x1234u16.to_be_bytes());
+
+        let mut mem = ZMemory::default();
+        mem.reset(data).unwrap();
+        mem
+    }
+
+    #[test]
+    fn v4_objects_use_wide_entries() {
+        let mut mem = v4_memory();
+        assert_eq!(mem.get_object_child(1).unwrap(), Some(2));
+        assert_eq!(mem.get_object_parent(2).unwrap(), 1);
+
+        mem.set_attr(2, 47).unwrap();
+        assert!(mem.test_attr(2, 47).unwrap());
+        assert!(!mem.test_attr(1, 47).unwrap());
+        assert!(mem.set_attr(2, 48).is_err());
+    }
+
+    #[test]
+    fn v4_properties_have_long_headers() {
+        let mem = v4_memory();
+        assert_eq!(u16::from(mem.get_prop(2, 5).unwrap()), 0x1234);
+        assert_eq!(mem.get_next_prop(2, 0).unwrap(), 40);
+        assert_eq!(mem.get_next_prop(2, 40).unwrap(), 5);
+        assert_eq!(mem.get_next_prop(2, 5).unwrap(), 0);
+
+        let addr = mem.get_prop_addr(2, 40).unwrap();
+        assert_eq!(addr, 0x280 + 3);
+        assert_eq!(mem.get_prop_len(addr).unwrap(), 64);
+    }
+}
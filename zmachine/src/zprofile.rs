@@ -0,0 +1,126 @@
+use crate::zheader::ZHeader;
+
+// Interpreter numbers Infocom gave its own ports (8.7.3.1). A few games
+// change their behaviour depending on which one they find in the header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Infocom {
+    Dec20 = 1,
+    AppleIIe = 2,
+    Macintosh = 3,
+    Amiga = 4,
+    AtariST = 5,
+    IbmPc = 6,
+    Commodore128 = 7,
+    Commodore64 = 8,
+    AppleIIc = 9,
+    AppleIIgs = 10,
+    TandyColor = 11,
+}
+
+// What the interpreter tells the game about itself. It's written into the
+// header when a story is loaded and again after a restart or restore.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterpreterProfile {
+    pub number: u8,
+    pub version: u8,
+    pub screen_lines: u8,
+    pub screen_width: u8,
+    pub standard: (u8, u8),
+
+    // V1-3 Flags 1
+    pub status_line: bool,
+    pub split_screen: bool,
+    pub variable_pitch: bool,
+
+    // V4+ Flags 1
+    pub colours: bool,
+    pub pictures: bool,
+    pub bold: bool,
+    pub italic: bool,
+    pub fixed_pitch: bool,
+    pub sound: bool,
+    pub timed_input: bool,
+
+    // V5+ Flags 2, features the game may ask for
+    pub undo: bool,
+    pub mouse: bool,
+    pub menus: bool,
+}
+
+impl Default for InterpreterProfile {
+    fn default() -> Self {
+        InterpreterProfile {
+            number: Infocom::IbmPc as u8,
+            version: b'A',
+            screen_lines: 24,
+            screen_width: 80,
+            standard: (1, 0),
+            status_line: true,
+            split_screen: true,
+            variable_pitch: false,
+            colours: false,
+            pictures: false,
+            bold: false,
+            italic: false,
+            fixed_pitch: true,
+            sound: true,
+            timed_input: true,
+            undo: true,
+            mouse: false,
+            menus: false,
+        }
+    }
+}
+
+impl InterpreterProfile {
+    // Infocom's own interpreters reported a letter for their version
+    pub fn infocom(machine: Infocom) -> Self {
+        let (screen_lines, screen_width, variable_pitch) = match machine {
+            Infocom::AppleIIe | Infocom::AppleIIc | Infocom::Commodore64 => (24, 40, false),
+            Infocom::Macintosh | Infocom::AppleIIgs => (24, 80, true),
+            Infocom::Amiga | Infocom::AtariST => (25, 80, true),
+            _ => (24, 80, false),
+        };
+
+        InterpreterProfile {
+            number: machine as u8,
+            version: b'F',
+            screen_lines,
+            screen_width,
+            variable_pitch,
+            ..InterpreterProfile::default()
+        }
+    }
+
+    pub(crate) fn apply<T: AsRef<[u8]> + AsMut<[u8]>>(&self, header: &mut ZHeader<T>) {
+        let version = header.version();
+
+        let flags1 = if version <= 3 {
+            bit(!self.status_line, 4) | bit(self.split_screen, 5) | bit(self.variable_pitch, 6)
+        } else {
+            bit(self.colours, 0) | bit(self.pictures, 1) | bit(self.bold, 2) | bit(self.italic, 3)
+                | bit(self.fixed_pitch, 4) | bit(self.sound, 5) | bit(self.timed_input, 7)
+        };
+        header.set_flags1(flags1);
+
+        let refused = bit16(!self.pictures, 3) | bit16(!self.undo, 4) | bit16(!self.mouse, 5)
+            | bit16(!self.colours, 6) | bit16(!self.sound, 7) | bit16(!self.menus, 8);
+        header.refuse_features(refused);
+
+        header.set_interpreter(self.number, self.version);
+        header.set_screen_size(self.screen_lines, self.screen_width);
+        if version >= 5 {
+            // everything is measured in characters
+            header.set_screen_units(self.screen_width as u16, self.screen_lines as u16, 1, 1);
+        }
+        header.set_standard_revision(self.standard.0, self.standard.1);
+    }
+}
+
+fn bit(on: bool, n: u8) -> u8 {
+    if on { 1 << n } else { 0 }
+}
+
+fn bit16(on: bool, n: u8) -> u16 {
+    if on { 1 << n } else { 0 }
+}
@@ -0,0 +1,81 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ZWindow {
+    Lower,
+    Upper,
+}
+
+#[derive(Debug)]
+pub(crate) struct ZScreen {
+    width: usize,
+    window: ZWindow,
+    upper: Vec<Vec<char>>,
+    cursor: (usize, usize),
+}
+
+impl Default for ZScreen {
+    fn default() -> Self {
+        ZScreen::new(80)
+    }
+}
+
+impl ZScreen {
+    pub(crate) fn new(width: usize) -> ZScreen {
+        ZScreen { width, window: ZWindow::Lower, upper: Vec::new(), cursor: (0, 0) }
+    }
+
+    pub(crate) fn window(&self) -> ZWindow {
+        self.window
+    }
+
+    pub(crate) fn split(&mut self, lines: usize, version: u8) {
+        self.upper.resize(lines, vec![' '; self.width]);
+
+        // only V3 clears the upper window when it's created
+        if version <= 3 {
+            for line in self.upper.iter_mut() {
+                line.iter_mut().for_each(|c| *c = ' ');
+            }
+        }
+
+        if self.cursor.0 >= lines {
+            self.cursor = (0, 0);
+        }
+        if lines == 0 {
+            self.window = ZWindow::Lower;
+        }
+    }
+
+    pub(crate) fn select(&mut self, window: ZWindow) {
+        self.window = window;
+        if window == ZWindow::Upper {
+            self.cursor = (0, 0);
+        }
+    }
+
+    // (row, column) in the upper window
+    pub(crate) fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    pub(crate) fn set_cursor(&mut self, row: usize, col: usize) {
+        self.cursor = (row, col);
+    }
+
+    pub(crate) fn print_upper(&mut self, text: &str) {
+        for c in text.chars() {
+            let (row, col) = self.cursor;
+            if c == '\n' {
+                self.cursor = (row + 1, 0);
+            } else if let Some(cell) = self.upper.get_mut(row).and_then(|line| line.get_mut(col)) {
+                *cell = c;
+                self.cursor = (row, col + 1);
+            }
+        }
+    }
+
+    pub(crate) fn upper_lines(&self) -> Vec<String> {
+        self.upper.iter()
+            .map(|line| line.iter().collect::<String>().trim_end().to_string())
+            .collect()
+    }
+}
@@ -0,0 +1,76 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoundAction {
+    Prepare,
+    Start,
+    Stop,
+    Finish,
+}
+
+impl SoundAction {
+    fn new(effect: u16) -> Option<Self> {
+        match effect {
+            1 => Some(SoundAction::Prepare),
+            2 => Some(SoundAction::Start),
+            3 => Some(SoundAction::Stop),
+            4 => Some(SoundAction::Finish),
+            _ => None,
+        }
+    }
+}
+
+// Numbers 1 and 2 are the built-in high and low bleeps, everything else comes
+// from the game's sound resources. A volume of 255 means loudest and a repeat
+// count of 255 means forever.
+pub trait ZSound {
+    fn sound_effect(&mut self, number: u16, action: SoundAction, volume: u8, repeats: u8);
+}
+
+#[derive(Default)]
+pub(crate) struct ZSoundHost {
+    backend: Option<Box<dyn ZSound>>,
+    routine: Option<u16>,
+    finished: Option<u16>,
+}
+
+impl fmt::Debug for ZSoundHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ZSoundHost")
+            .field("backend", &self.backend.is_some())
+            .field("routine", &self.routine)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+impl ZSoundHost {
+    pub(crate) fn set_backend(&mut self, backend: Option<Box<dyn ZSound>>) {
+        self.backend = backend;
+    }
+
+    pub(crate) fn effect(&mut self, number: u16, effect: u16, volume: u8, repeats: u8, routine: u16) {
+        let action = match SoundAction::new(effect) {
+            Some(action) => action,
+            None => return,
+        };
+
+        match action {
+            SoundAction::Start => self.routine = if routine != 0 { Some(routine) } else { None },
+            SoundAction::Stop | SoundAction::Finish => self.routine = None,
+            SoundAction::Prepare => {},
+        }
+
+        if let Some(backend) = self.backend.as_mut() {
+            backend.sound_effect(number, action, volume, repeats);
+        }
+    }
+
+    pub(crate) fn finished(&mut self) {
+        self.finished = self.routine.take();
+    }
+
+    pub(crate) fn take_finished(&mut self) -> Option<u16> {
+        self.finished.take()
+    }
+}
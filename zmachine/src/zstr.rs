@@ -34,7 +34,8 @@ impl<'a> Iterator for ZCharIter<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(word_idx) = self.word_idx.take() {
-            let word: ZWord = (self.mem[word_idx], self.mem[word_idx + 1]).into();
+            // a string that runs off the end of memory just stops
+            let word: ZWord = (*self.mem.get(word_idx)?, *self.mem.get(word_idx + 1)?).into();
             let zch = ZCharWord::new(word.into());
 
             let idx = self.char_idx;
@@ -63,9 +64,9 @@ impl<'a> Iterator for ZCharIter<'a> {
     }
 }
 
-static ALPH_A0: &'static str = "abcdefghijklmnopqrstuvwxyz";
-static ALPH_A1: &'static str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
-static ALPH_A2: &'static str = " \n0123456789.,!?_#'\"/\\-:()";
+static ALPH_A0: &str = "abcdefghijklmnopqrstuvwxyz";
+static ALPH_A1: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+static ALPH_A2: &str = " \n0123456789.,!?_#'\"/\\-:()";
 
 #[derive(Debug, PartialEq)]
 enum Alphabet {
@@ -127,9 +128,18 @@ impl ZChar {
 
     pub fn encode(c: u8) -> Vec<Self> {
         let mut ret: Vec<ZChar> = Vec::new();
-        let (alph, ch) = Alphabet::lookup(c);
+        if c == b' ' {
+            ret.push(ZChar::Char(0));
+            return ret;
+        }
 
+        let (alph, ch) = Alphabet::lookup(c);
         match alph {
+            Alphabet::A0 if ch == 0 => {
+                // not in any alphabet, so it's spelled out as a 10 bit ZSCII code
+                ret.extend([ZChar::Shift(5), ZChar::Char(6), ZChar::Char(c >> 5), ZChar::Char(c & 0x1F)]);
+                ret
+            },
             Alphabet::A0 => {
                 ret.push(ZChar::Char(ch));
                 ret
@@ -219,8 +229,11 @@ impl ZString {
 
             if let Some(aidx) = abbrev_idx.take() {
                 let idx = (aidx + zc as usize) * 2;
-                let addr: u16 = ZWord::from((abbrev_table[idx], abbrev_table[idx + 1])).into();
-                let abbrev_iter = ZCharIter::new(&mem[addr as usize * 2..]);
+                let addr: u16 = match abbrev_table.get(idx..idx + 2) {
+                    Some(entry) => ZWord::from((entry[0], entry[1])).into(),
+                    None => continue,
+                };
+                let abbrev_iter = ZCharIter::new(mem.get(addr as usize * 2..).unwrap_or(&[]));
                 let (_, chs) = ZString::parse_into(abbrev_iter, mem, abbrev_table, chars);
                 chars = chs;
             } else if let Some(ref mut zsc) = zscii {
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::io::{BufRead, Write};
+
+pub(crate) struct ZStreams {
+    screen: bool,
+    transcript: Option<Box<dyn Write>>,
+    commands: Option<Box<dyn Write>>,
+    recording: bool,
+    tables: Vec<(usize, Vec<u8>)>,
+    replay: Option<Box<dyn BufRead>>,
+    replaying: bool,
+}
+
+impl Default for ZStreams {
+    fn default() -> Self {
+        ZStreams {
+            screen: true,
+            transcript: None,
+            commands: None,
+            recording: false,
+            tables: Vec::new(),
+            replay: None,
+            replaying: false,
+        }
+    }
+}
+
+impl fmt::Debug for ZStreams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ZStreams")
+            .field("screen", &self.screen)
+            .field("transcript", &self.transcript.is_some())
+            .field("commands", &self.commands.is_some())
+            .field("recording", &self.recording)
+            .field("tables", &self.tables)
+            .field("replay", &self.replay.is_some())
+            .field("replaying", &self.replaying)
+            .finish()
+    }
+}
+
+impl ZStreams {
+    pub(crate) fn screen(&self) -> bool {
+        self.screen
+    }
+
+    pub(crate) fn select_screen(&mut self, on: bool) {
+        self.screen = on;
+    }
+
+    pub(crate) fn set_transcript(&mut self, writer: Option<Box<dyn Write>>) {
+        self.transcript = writer;
+    }
+
+    pub(crate) fn set_commands(&mut self, writer: Option<Box<dyn Write>>) {
+        self.recording = writer.is_some();
+        self.commands = writer;
+    }
+
+    pub(crate) fn select_commands(&mut self, on: bool) {
+        self.recording = on;
+    }
+
+    // stream 3 nests up to 16 deep, anything past that is dropped
+    pub(crate) fn open_table(&mut self, addr: usize) {
+        if self.tables.len() < 16 {
+            self.tables.push((addr, Vec::new()));
+        }
+    }
+
+    pub(crate) fn close_table(&mut self) -> Option<(usize, Vec<u8>)> {
+        self.tables.pop()
+    }
+
+    // while a table is selected it swallows everything else
+    pub(crate) fn capture(&mut self, text: &str) -> bool {
+        if let Some((_, buf)) = self.tables.last_mut() {
+            buf.extend(text.chars().map(|c| if c == '\n' { 13 } else { c as u8 }));
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn transcribe(&mut self, text: &str) {
+        if let Some(w) = self.transcript.as_mut() {
+            let _ = w.write_all(text.as_bytes());
+        }
+    }
+
+    pub(crate) fn record(&mut self, command: &str) {
+        if !self.recording {
+            return;
+        }
+
+        if let Some(w) = self.commands.as_mut() {
+            let _ = writeln!(w, "{}", command);
+        }
+    }
+
+    pub(crate) fn set_replay(&mut self, reader: Option<Box<dyn BufRead>>) {
+        self.replaying = reader.is_some();
+        self.replay = reader;
+    }
+
+    pub(crate) fn select_replay(&mut self, on: bool) {
+        self.replaying = on;
+    }
+
+    // input stream 1 hands back to the keyboard once the file runs dry
+    pub(crate) fn next_command(&mut self) -> Option<String> {
+        if !self.replaying {
+            return None;
+        }
+
+        let mut line = String::new();
+        match self.replay.as_mut().map(|r| r.read_line(&mut line)) {
+            Some(Ok(n)) if n > 0 => Some(line),
+            _ => {
+                self.replaying = false;
+                None
+            },
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        for w in self.transcript.iter_mut().chain(self.commands.iter_mut()) {
+            let _ = w.flush();
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Anything that counts time forward. Only differences between readings
+// matter, so it can start wherever it likes.
+pub trait ZClock {
+    fn now(&self) -> Duration;
+}
+
+struct SystemClock;
+
+impl ZClock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+    }
+}
+
+// read and read_char can ask for an interrupt routine to be called every
+// so many tenths of a second while the player hasn't finished typing.
+pub(crate) struct ZTimer {
+    clock: Box<dyn ZClock>,
+    tenths: u16,
+    routine: u16,
+    deadline: Duration,
+    timed_out: bool,
+    running: bool,
+    result: Option<u16>,
+}
+
+impl Default for ZTimer {
+    fn default() -> Self {
+        ZTimer {
+            clock: Box::new(SystemClock),
+            tenths: 0,
+            routine: 0,
+            deadline: Duration::ZERO,
+            timed_out: false,
+            running: false,
+            result: None,
+        }
+    }
+}
+
+impl fmt::Debug for ZTimer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ZTimer")
+            .field("tenths", &self.tenths)
+            .field("routine", &self.routine)
+            .field("deadline", &self.deadline)
+            .field("timed_out", &self.timed_out)
+            .field("running", &self.running)
+            .field("result", &self.result)
+            .finish()
+    }
+}
+
+impl ZTimer {
+    pub(crate) fn set_clock(&mut self, clock: Box<dyn ZClock>) {
+        self.clock = clock;
+    }
+
+    // a time or routine of 0 means the input isn't timed
+    pub(crate) fn start(&mut self, tenths: u16, routine: u16) {
+        if tenths == 0 || routine == 0 {
+            self.stop();
+            return;
+        }
+
+        self.tenths = tenths;
+        self.routine = routine;
+        self.timed_out = false;
+        self.restart();
+    }
+
+    pub(crate) fn restart(&mut self) {
+        self.deadline = self.clock.now() + Duration::from_millis(self.tenths as u64 * 100);
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.tenths = 0;
+        self.routine = 0;
+        self.timed_out = false;
+        self.running = false;
+        self.result = None;
+    }
+
+    pub(crate) fn tenths(&self) -> Option<u16> {
+        if self.tenths != 0 { Some(self.tenths) } else { None }
+    }
+
+    pub(crate) fn time_left(&self) -> Option<Duration> {
+        self.tenths()?;
+        Some(self.deadline.saturating_sub(self.clock.now()))
+    }
+
+    pub(crate) fn time_out(&mut self) {
+        self.timed_out = self.tenths().is_some();
+    }
+
+    // the routine to run for a timeout the host reported
+    pub(crate) fn take_timeout(&mut self) -> Option<u16> {
+        if std::mem::take(&mut self.timed_out) {
+            Some(self.routine)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn interrupt(&mut self) {
+        self.running = true;
+    }
+
+    pub(crate) fn running(&self) -> bool {
+        self.running
+    }
+
+    pub(crate) fn returned(&mut self, val: u16) {
+        self.running = false;
+        self.result = Some(val);
+    }
+
+    pub(crate) fn take_result(&mut self) -> Option<u16> {
+        self.result.take()
+    }
+}
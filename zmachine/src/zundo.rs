@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+
+// runs of bytes that differ, as (offset, the bytes to put back)
+type Diff = Vec<(usize, Vec<u8>)>;
+
+// In-memory undo states, oldest first. Only the newest keeps a full copy of
+// dynamic memory, the rest keep the bytes that differ from the one after.
+#[derive(Debug)]
+pub(crate) struct ZUndo<T> {
+    depth: usize,
+    states: VecDeque<(T, Diff)>,
+    latest: Vec<u8>,
+}
+
+impl<T> Default for ZUndo<T> {
+    fn default() -> Self {
+        ZUndo { depth: 10, states: VecDeque::new(), latest: Vec::new() }
+    }
+}
+
+impl<T> ZUndo<T> {
+    pub(crate) fn set_depth(&mut self, depth: usize) {
+        self.depth = depth;
+        while self.states.len() > depth {
+            self.states.pop_front();
+        }
+        if self.states.is_empty() {
+            self.latest.clear();
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.depth > 0
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.states.clear();
+        self.latest.clear();
+    }
+
+    pub(crate) fn push(&mut self, memory: &[u8], state: T) {
+        if self.depth == 0 {
+            return;
+        }
+
+        if let Some((_, diff)) = self.states.back_mut() {
+            *diff = changes(&self.latest, memory);
+        }
+        self.latest = memory.to_vec();
+        self.states.push_back((state, Vec::new()));
+
+        while self.states.len() > self.depth {
+            self.states.pop_front();
+        }
+    }
+
+    // the newest state and its memory
+    pub(crate) fn pop(&mut self) -> Option<(T, Vec<u8>)> {
+        let (state, _) = self.states.pop_back()?;
+        let memory = self.latest.clone();
+
+        match self.states.back_mut() {
+            Some((_, diff)) => {
+                for (start, run) in diff.drain(..) {
+                    self.latest[start..start + run.len()].copy_from_slice(&run);
+                }
+            },
+            None => self.latest.clear(),
+        }
+
+        Some((state, memory))
+    }
+}
+
+// where `from` differs from `to`, with the bytes from `from`
+fn changes(from: &[u8], to: &[u8]) -> Diff {
+    let mut runs: Diff = Vec::new();
+    for (i, (a, b)) in from.iter().zip(to).enumerate() {
+        if a == b {
+            continue;
+        }
+
+        match runs.last_mut() {
+            Some((start, run)) if *start + run.len() == i => run.push(*a),
+            _ => runs.push((i, vec![*a])),
+        }
+    }
+
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states_come_back_newest_first() {
+        let mut undo: ZUndo<u8> = ZUndo::default();
+        undo.push(&[0, 0, 0, 0], 1);
+        undo.push(&[0, 7, 7, 0], 2);
+        undo.push(&[9, 7, 7, 9], 3);
+
+        assert_eq!(undo.pop(), Some((3, vec![9, 7, 7, 9])));
+        assert_eq!(undo.pop(), Some((2, vec![0, 7, 7, 0])));
+        assert_eq!(undo.pop(), Some((1, vec![0, 0, 0, 0])));
+        assert_eq!(undo.pop(), None);
+    }
+
+    #[test]
+    fn only_the_newest_states_are_kept() {
+        let mut undo: ZUndo<u8> = ZUndo::default();
+        undo.set_depth(2);
+        for n in 1..=4 {
+            undo.push(&[n], n);
+        }
+
+        assert_eq!(undo.pop(), Some((4, vec![4])));
+        assert_eq!(undo.pop(), Some((3, vec![3])));
+        assert_eq!(undo.pop(), None);
+    }
+}
@@ -1,4 +1,76 @@
+use zmachine::{ZMachine, ZMachineExecResult, LoadError, ZHeader, InterpreterProfile, Infocom};
+
 #[test]
 fn it_works() {
-    assert!(1 == 1);
+    let mut machine = ZMachine::new();
+    let warnings = machine.load("../games/zork").unwrap();
+    assert!(warnings.is_empty());
+
+    let mut output: Vec<u8> = Vec::new();
+    match machine.exec(&mut output) {
+        ZMachineExecResult::ShowStatus(status) => assert_eq!(status.location, "West of House"),
+        _ => panic!("zork should draw its status line"),
+    }
+
+    match machine.exec(&mut output) {
+        ZMachineExecResult::NeedInput => {},
+        _ => panic!("zork should be waiting for input"),
+    }
+
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("ZORK"));
+}
+
+#[test]
+fn padding_past_the_file_length_is_ignored() {
+    let data = std::fs::read("../games/ZORK1.DAT").unwrap();
+
+    let mut machine = ZMachine::new();
+    let warnings = machine.load_reader(&data[..]).unwrap();
+    assert!(warnings.is_empty());
+}
+
+#[test]
+fn truncated_stories_are_rejected() {
+    let data = std::fs::read("../games/zork").unwrap();
+
+    let mut machine = ZMachine::new();
+    match machine.load_bytes(&data[..data.len() / 2]) {
+        Err(LoadError::TooShort { length, needed }) => {
+            assert_eq!(length, data.len() / 2);
+            assert_eq!(needed, data.len());
+        },
+        _ => panic!("half a story shouldn't load"),
+    }
+}
+
+#[test]
+fn header_fields_are_readable() {
+    let data = std::fs::read("../games/zork").unwrap();
+    let header = ZHeader::new(&data[..]).unwrap();
+
+    assert_eq!(header.version(), 3);
+    assert_eq!(header.release(), 119);
+    assert_eq!(&header.serial(), b"880429");
+    assert_eq!(header.file_length(), data.len());
+
+    let mut machine = ZMachine::new();
+    machine.load_bytes(data.clone()).unwrap();
+    assert_eq!(machine.header().unwrap().checksum(), header.checksum());
+}
+
+#[test]
+fn the_interpreter_profile_survives_a_restart() {
+    let mut machine = ZMachine::new();
+    machine.load("../games/zork").unwrap();
+    assert_eq!(machine.header().unwrap().interpreter_number(), 6);
+
+    machine.set_profile(InterpreterProfile::infocom(Infocom::Amiga));
+    machine.restart();
+
+    let header = machine.header().unwrap();
+    assert_eq!(header.interpreter_number(), Infocom::Amiga as u8);
+    assert_eq!(header.screen_size(), (25, 80));
+    assert_eq!(header.standard_revision(), (1, 0));
+    assert_eq!(header.flags1() & 0x70, 0x60);
 }